@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     io::{Error, ErrorKind},
     time::SystemTime,
     u16,
@@ -7,43 +8,130 @@ use std::{
 use pleco::{BitMove, Board};
 use serde::{de, ser::SerializeStruct, Deserialize, Serialize};
 
+#[derive(Clone)]
 pub struct ChessGame {
     initial_board: Board,
     moves: Vec<Move>,
-    start_time: u32, // in milliseconds
+    start_time: u64, // in milliseconds since the epoch
     time_limit: u32, // in milliseconds
-    increment: u32,  // in milliseconds
+    time_control: TimeControl,
+    timeout_insufficient_material_is_draw: bool,
+    draw_claim_window: Option<u16>, // in plies
+    auto_draw_on_insufficient_material: bool,
+    auto_first_move_time: bool,
+    black_time_limit: Option<u32>, // in milliseconds, overrides time_limit for black
+    white_name: Option<String>,
+    black_name: Option<String>,
+    event: Option<String>,
+    site: Option<String>,
+    date: Option<String>,
+    round: Option<String>,
+    time_control_stages: Vec<TimeControlStage>, // empty unless built with stages
+    result_override: Option<GameResult>,        // set by a resignation or an agreed draw
+    chess960_castling: Option<String>,          // Chess960 castling rights pleco cannot hold
 }
 impl Serialize for ChessGame {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        let mut state = serializer.serialize_struct("ChessGame", 5)?;
-        state.serialize_field("initial_board", &self.initial_board.fen())?;
+        let mut state = serializer.serialize_struct("ChessGame", 19)?;
+        state.serialize_field("initial_board", &self.initial_fen())?;
         state.serialize_field("moves", &self.moves)?;
         state.serialize_field("start_time", &self.start_time)?;
         state.serialize_field("time_limit", &self.time_limit)?;
-        state.serialize_field("increment", &self.increment)?;
+        state.serialize_field("time_control", &self.time_control)?;
+        state.serialize_field(
+            "timeout_insufficient_material_is_draw",
+            &self.timeout_insufficient_material_is_draw,
+        )?;
+        state.serialize_field("draw_claim_window", &self.draw_claim_window)?;
+        state.serialize_field(
+            "auto_draw_on_insufficient_material",
+            &self.auto_draw_on_insufficient_material,
+        )?;
+        state.serialize_field("auto_first_move_time", &self.auto_first_move_time)?;
+        state.serialize_field("black_time_limit", &self.black_time_limit)?;
+        state.serialize_field("white_name", &self.white_name)?;
+        state.serialize_field("black_name", &self.black_name)?;
+        state.serialize_field("event", &self.event)?;
+        state.serialize_field("site", &self.site)?;
+        state.serialize_field("date", &self.date)?;
+        state.serialize_field("round", &self.round)?;
+        state.serialize_field("time_control_stages", &self.time_control_stages)?;
+        state.serialize_field("result_override", &self.result_override)?;
+        state.serialize_field("version", &SERIALIZATION_VERSION)?;
         state.end()
     }
 }
+/// Version of the serialized `ChessGame` format, written as its `version` field, which is the
+/// last element of sequence payloads. Version 1 (no `version` field) had an integer
+/// `increment` instead of `time_control` and a `start_time` truncated to 32 bits.
+const SERIALIZATION_VERSION: u32 = 2;
+
+fn check_serialization_version<E: de::Error>(version: u32) -> Result<u32, E> {
+    if (1..=SERIALIZATION_VERSION).contains(&version) {
+        Ok(version)
+    } else {
+        Err(E::custom(format!(
+            "unsupported serialization version {}",
+            version
+        )))
+    }
+}
+
+/// Deserialization error for an unreadable initial FEN
+fn fen_error<E: de::Error>(err: ChessError) -> E {
+    match err {
+        ChessError::InvalidFen(reason) => E::custom(format!("invalid fen: {}", reason)),
+        err => E::custom(err),
+    }
+}
+
+fn upgrade_start_time(start_time: u64, version: u32) -> u64 {
+    if version == 1 {
+        restore_truncated_start_time(start_time, current_millis())
+    } else {
+        start_time
+    }
+}
+
 impl<'de> Deserialize<'de> for ChessGame {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
         #[derive(Deserialize)]
-        #[serde(field_identifier, rename_all = "lowercase")]
+        #[serde(field_identifier, rename_all = "snake_case")]
         enum Field {
-            Initial_Board,
+            InitialBoard,
             Moves,
-            Start_Time,
-            Time_Limit,
+            StartTime,
+            TimeLimit,
             Increment,
+            TimeControl,
+            TimeoutInsufficientMaterialIsDraw,
+            DrawClaimWindow,
+            AutoDrawOnInsufficientMaterial,
+            AutoFirstMoveTime,
+            BlackTimeLimit,
+            WhiteName,
+            BlackName,
+            Event,
+            Site,
+            Date,
+            Round,
+            TimeControlStages,
+            ResultOverride,
+            Version,
         }
 
-        struct ChessGameVisitor;
+        /// `human_readable` formats (JSON and the like) describe their own types, so a sequence
+        /// payload may still hold version 1's integer increment. Binary formats such as bincode
+        /// cannot tell it apart and always hold a `TimeControl`.
+        struct ChessGameVisitor {
+            human_readable: bool,
+        }
         impl<'de> serde::de::Visitor<'de> for ChessGameVisitor {
             type Value = ChessGame;
 
@@ -60,9 +148,24 @@ impl<'de> Deserialize<'de> for ChessGame {
                 let mut start_time = None;
                 let mut time_limit = None;
                 let mut increment = None;
+                let mut time_control = None;
+                let mut timeout_insufficient_material_is_draw = None;
+                let mut draw_claim_window = None;
+                let mut auto_draw_on_insufficient_material = None;
+                let mut auto_first_move_time = None;
+                let mut black_time_limit = None;
+                let mut white_name = None;
+                let mut black_name = None;
+                let mut event = None;
+                let mut site = None;
+                let mut date = None;
+                let mut round = None;
+                let mut time_control_stages = None;
+                let mut result_override = None;
+                let mut version = None;
                 while let Some(key) = map.next_key()? {
                     match key {
-                        Field::Initial_Board => {
+                        Field::InitialBoard => {
                             if initial_board_string.is_some() {
                                 return Err(de::Error::duplicate_field("initial_board"));
                             }
@@ -70,49 +173,185 @@ impl<'de> Deserialize<'de> for ChessGame {
                         }
                         Field::Moves => {
                             if moves.is_some() {
-                                return Err(de::Error::duplicate_field("initial_board"));
+                                return Err(de::Error::duplicate_field("moves"));
                             }
                             moves = Some(map.next_value()?);
                         }
-                        Field::Start_Time => {
+                        Field::StartTime => {
                             if start_time.is_some() {
-                                return Err(de::Error::duplicate_field("initial_board"));
+                                return Err(de::Error::duplicate_field("start_time"));
                             }
                             start_time = Some(map.next_value()?);
                         }
-                        Field::Time_Limit => {
+                        Field::TimeLimit => {
                             if time_limit.is_some() {
-                                return Err(de::Error::duplicate_field("initial_board"));
+                                return Err(de::Error::duplicate_field("time_limit"));
                             }
                             time_limit = Some(map.next_value()?);
                         }
                         Field::Increment => {
                             if increment.is_some() {
-                                return Err(de::Error::duplicate_field("initial_board"));
+                                return Err(de::Error::duplicate_field("increment"));
                             }
                             increment = Some(map.next_value()?);
                         }
+                        Field::TimeControl => {
+                            if time_control.is_some() {
+                                return Err(de::Error::duplicate_field("time_control"));
+                            }
+                            time_control = Some(map.next_value()?);
+                        }
+                        Field::TimeoutInsufficientMaterialIsDraw => {
+                            if timeout_insufficient_material_is_draw.is_some() {
+                                return Err(de::Error::duplicate_field(
+                                    "timeout_insufficient_material_is_draw",
+                                ));
+                            }
+                            timeout_insufficient_material_is_draw = Some(map.next_value()?);
+                        }
+                        Field::DrawClaimWindow => {
+                            if draw_claim_window.is_some() {
+                                return Err(de::Error::duplicate_field("draw_claim_window"));
+                            }
+                            draw_claim_window = Some(map.next_value()?);
+                        }
+                        Field::AutoDrawOnInsufficientMaterial => {
+                            if auto_draw_on_insufficient_material.is_some() {
+                                return Err(de::Error::duplicate_field(
+                                    "auto_draw_on_insufficient_material",
+                                ));
+                            }
+                            auto_draw_on_insufficient_material = Some(map.next_value()?);
+                        }
+                        Field::AutoFirstMoveTime => {
+                            if auto_first_move_time.is_some() {
+                                return Err(de::Error::duplicate_field("auto_first_move_time"));
+                            }
+                            auto_first_move_time = Some(map.next_value()?);
+                        }
+                        Field::BlackTimeLimit => {
+                            if black_time_limit.is_some() {
+                                return Err(de::Error::duplicate_field("black_time_limit"));
+                            }
+                            black_time_limit = Some(map.next_value()?);
+                        }
+                        Field::WhiteName => {
+                            if white_name.is_some() {
+                                return Err(de::Error::duplicate_field("white_name"));
+                            }
+                            white_name = Some(map.next_value()?);
+                        }
+                        Field::BlackName => {
+                            if black_name.is_some() {
+                                return Err(de::Error::duplicate_field("black_name"));
+                            }
+                            black_name = Some(map.next_value()?);
+                        }
+                        Field::Event => {
+                            if event.is_some() {
+                                return Err(de::Error::duplicate_field("event"));
+                            }
+                            event = Some(map.next_value()?);
+                        }
+                        Field::Site => {
+                            if site.is_some() {
+                                return Err(de::Error::duplicate_field("site"));
+                            }
+                            site = Some(map.next_value()?);
+                        }
+                        Field::Date => {
+                            if date.is_some() {
+                                return Err(de::Error::duplicate_field("date"));
+                            }
+                            date = Some(map.next_value()?);
+                        }
+                        Field::Round => {
+                            if round.is_some() {
+                                return Err(de::Error::duplicate_field("round"));
+                            }
+                            round = Some(map.next_value()?);
+                        }
+                        Field::TimeControlStages => {
+                            if time_control_stages.is_some() {
+                                return Err(de::Error::duplicate_field("time_control_stages"));
+                            }
+                            time_control_stages = Some(map.next_value()?);
+                        }
+                        Field::ResultOverride => {
+                            if result_override.is_some() {
+                                return Err(de::Error::duplicate_field("result_override"));
+                            }
+                            result_override = Some(map.next_value()?);
+                        }
+                        Field::Version => {
+                            if version.is_some() {
+                                return Err(de::Error::duplicate_field("version"));
+                            }
+                            version = Some(map.next_value()?);
+                        }
                     }
                 }
                 let initial_board_string = initial_board_string
                     .ok_or_else(|| de::Error::missing_field("initial_board"))?;
-                let moves = moves.ok_or_else(|| de::Error::missing_field("initial_board"))?;
-                let increment =
-                    increment.ok_or_else(|| de::Error::missing_field("initial_board"))?;
+                let mut moves: Vec<Move> =
+                    moves.ok_or_else(|| de::Error::missing_field("moves"))?;
+                // Payloads from before the version field are version 1
+                let version = check_serialization_version(version.unwrap_or(1))?;
+                // Version 1 only had an increment
+                let time_control = match (time_control, increment) {
+                    (Some(time_control), _) => time_control,
+                    (None, Some(increment)) if version == 1 => {
+                        TimeControl::from_increment(increment)
+                    }
+                    (None, _) => return Err(de::Error::missing_field("time_control")),
+                };
                 let start_time =
-                    start_time.ok_or_else(|| de::Error::missing_field("initial_board"))?;
+                    start_time.ok_or_else(|| de::Error::missing_field("start_time"))?;
+                let start_time = upgrade_start_time(start_time, version);
                 let time_limit =
-                    time_limit.ok_or_else(|| de::Error::missing_field("initial_board"))?;
+                    time_limit.ok_or_else(|| de::Error::missing_field("time_limit"))?;
+                // Games serialized before this option existed follow the FIDE rule
+                let timeout_insufficient_material_is_draw =
+                    timeout_insufficient_material_is_draw.unwrap_or(true);
+                let draw_claim_window = draw_claim_window.unwrap_or(None);
+                let auto_draw_on_insufficient_material =
+                    auto_draw_on_insufficient_material.unwrap_or(true);
+                let auto_first_move_time = auto_first_move_time.unwrap_or(false);
+                let black_time_limit = black_time_limit.unwrap_or(None);
+                let white_name = white_name.unwrap_or(None);
+                let black_name = black_name.unwrap_or(None);
+                let event = event.unwrap_or(None);
+                let site = site.unwrap_or(None);
+                let date = date.unwrap_or(None);
+                let round = round.unwrap_or(None);
+                let time_control_stages = time_control_stages.unwrap_or_default();
+                let result_override = check_result_override(result_override.unwrap_or(None))?;
 
-                let initial_board =
-                    Board::from_fen(initial_board_string).expect("invalid fen provided");
+                let (initial_board, chess960_castling) =
+                    board_from_fen(initial_board_string).map_err(fen_error)?;
+                check_side_not_to_move(&initial_board).map_err(de::Error::custom)?;
+                resolve_moves(&initial_board, &mut moves);
 
                 Ok(ChessGame {
                     initial_board,
                     moves,
-                    increment,
+                    time_control,
                     start_time,
                     time_limit,
+                    timeout_insufficient_material_is_draw,
+                    draw_claim_window,
+                    auto_draw_on_insufficient_material,
+                    auto_first_move_time,
+                    black_time_limit,
+                    white_name,
+                    black_name,
+                    event,
+                    site,
+                    date,
+                    round,
+                    time_control_stages,
+                    result_override,
+                    chess960_castling,
                 })
             }
             fn visit_seq<V>(self, mut seq: V) -> Result<ChessGame, V::Error>
@@ -122,24 +361,83 @@ impl<'de> Deserialize<'de> for ChessGame {
                 let inital_board: String = seq
                     .next_element()?
                     .ok_or_else(|| serde::de::Error::invalid_length(0, &self))?;
-                let moves: Vec<Move> = seq
+                let mut moves: Vec<Move> = seq
                     .next_element()?
                     .ok_or_else(|| serde::de::Error::invalid_length(1, &self))?;
-                let start_time: u32 = seq
+                let start_time: u64 = seq
                     .next_element()?
                     .ok_or_else(|| serde::de::Error::invalid_length(2, &self))?;
                 let time_limit: u32 = seq
                     .next_element()?
                     .ok_or_else(|| serde::de::Error::invalid_length(3, &self))?;
-                let increment: u32 = seq
-                    .next_element()?
-                    .ok_or_else(|| serde::de::Error::invalid_length(4, &self))?;
+                let (time_control, legacy_increment) = if self.human_readable {
+                    let value: serde_json::Value = seq
+                        .next_element()?
+                        .ok_or_else(|| serde::de::Error::invalid_length(4, &self))?;
+                    match value.as_u64() {
+                        Some(increment) => {
+                            let increment = u32::try_from(increment).map_err(de::Error::custom)?;
+                            (TimeControl::from_increment(increment), true)
+                        }
+                        None => (
+                            TimeControl::deserialize(value).map_err(de::Error::custom)?,
+                            false,
+                        ),
+                    }
+                } else {
+                    let time_control: TimeControl = seq
+                        .next_element()?
+                        .ok_or_else(|| serde::de::Error::invalid_length(4, &self))?;
+                    (time_control, false)
+                };
+                let timeout_insufficient_material_is_draw: bool =
+                    seq.next_element()?.unwrap_or(true);
+                let draw_claim_window: Option<u16> = seq.next_element()?.unwrap_or(None);
+                let auto_draw_on_insufficient_material: bool = seq.next_element()?.unwrap_or(true);
+                let auto_first_move_time: bool = seq.next_element()?.unwrap_or(false);
+                let black_time_limit: Option<u32> = seq.next_element()?.unwrap_or(None);
+                let white_name: Option<String> = seq.next_element()?.unwrap_or(None);
+                let black_name: Option<String> = seq.next_element()?.unwrap_or(None);
+                let event: Option<String> = seq.next_element()?.unwrap_or(None);
+                let site: Option<String> = seq.next_element()?.unwrap_or(None);
+                let date: Option<String> = seq.next_element()?.unwrap_or(None);
+                let round: Option<String> = seq.next_element()?.unwrap_or(None);
+                let (initial_board, chess960_castling) =
+                    board_from_fen(&inital_board).map_err(fen_error)?;
+                check_side_not_to_move(&initial_board).map_err(de::Error::custom)?;
+                resolve_moves(&initial_board, &mut moves);
+                let time_control_stages: Vec<TimeControlStage> =
+                    seq.next_element()?.unwrap_or_default();
+                let result_override = check_result_override(seq.next_element()?.unwrap_or(None))?;
+                let version = check_serialization_version(seq.next_element()?.unwrap_or(1))?;
+                // Version 1 only had an increment
+                if legacy_increment && version != 1 {
+                    return Err(de::Error::invalid_type(
+                        de::Unexpected::Other("integer increment"),
+                        &"a time control",
+                    ));
+                }
+                let start_time = upgrade_start_time(start_time, version);
                 Ok(ChessGame {
-                    initial_board: Board::from_fen(&inital_board).unwrap(),
+                    initial_board,
                     moves,
                     start_time,
                     time_limit,
-                    increment,
+                    time_control,
+                    timeout_insufficient_material_is_draw,
+                    draw_claim_window,
+                    auto_draw_on_insufficient_material,
+                    auto_first_move_time,
+                    black_time_limit,
+                    white_name,
+                    black_name,
+                    event,
+                    site,
+                    date,
+                    round,
+                    time_control_stages,
+                    result_override,
+                    chess960_castling,
                 })
             }
         }
@@ -149,15 +447,35 @@ impl<'de> Deserialize<'de> for ChessGame {
             "start_time",
             "time_limit",
             "increment",
+            "time_control",
+            "timeout_insufficient_material_is_draw",
+            "draw_claim_window",
+            "auto_draw_on_insufficient_material",
+            "auto_first_move_time",
+            "black_time_limit",
+            "white_name",
+            "black_name",
+            "event",
+            "site",
+            "date",
+            "round",
+            "time_control_stages",
+            "result_override",
+            "version",
         ];
-        deserializer.deserialize_struct("ChessGame", FIELDS, ChessGameVisitor)
+        let human_readable = deserializer.is_human_readable();
+        deserializer.deserialize_struct("ChessGame", FIELDS, ChessGameVisitor { human_readable })
     }
 }
 impl ChessGame {
+    /// Moves played so far, in order
+    pub fn moves(&self) -> &[Move] {
+        &self.moves
+    }
     pub fn compute_current_board(&self) -> Board {
         let mut board = self.initial_board.clone();
         for mov in self.moves.iter() {
-            board.apply_uci_move(&mov.uci_move); //Assumes all the previous moves were valid
+            apply_game_move(&mut board, mov); //Assumes all the previous moves were valid
         }
         board
     }
@@ -165,48 +483,226 @@ impl ChessGame {
         let mut board = self.initial_board.clone();
         for (turn, mov) in self.moves.iter().enumerate() {
             if (turn as u16) < target_turn {
-                board.apply_uci_move(&mov.uci_move); //Assumes all the previous moves were valid
+                apply_game_move(&mut board, mov); //Assumes all the previous moves were valid
             } else {
                 break;
             }
         }
         board
     }
+    /// Every position of the game in a single replay: the initial board, then the board
+    /// after each move, so the `n`th item is `compute_board_at_turn(n)`. Prefer this over
+    /// calling `compute_board_at_turn` in a loop, which replays the game each time.
+    pub fn positions(&self) -> impl Iterator<Item = Board> + '_ {
+        let mut board = self.initial_board.clone();
+        std::iter::once(board.clone()).chain(self.moves.iter().map(move |mov| {
+            apply_game_move(&mut board, mov); //Assumes all the previous moves were valid
+            board.clone()
+        }))
+    }
+    /// The current position as text from white's side, see `render_from`
+    pub fn render_ascii(&self) -> String {
+        self.render_from(pleco::Player::White, false)
+    }
+    /// `render_ascii` with figurine glyphs, e.g. ♔ for the white king
+    pub fn render_unicode(&self) -> String {
+        self.render_from(pleco::Player::White, true)
+    }
+    /// The current position as an 8x8 grid seen from `perspective`'s side, ranks labelled on
+    /// the left and files below. Pieces are FEN letters (uppercase white) or, with `unicode`,
+    /// figurines; empty squares are '.'.
+    pub fn render_from(&self, perspective: pleco::Player, unicode: bool) -> String {
+        let fen = self.compute_current_board().fen();
+        let mut ranks: Vec<Vec<char>> = fen
+            .split(' ')
+            .next()
+            .unwrap_or_default()
+            .split('/')
+            .map(|rank| {
+                rank.chars()
+                    .flat_map(|c| match c.to_digit(10) {
+                        Some(empty) => vec!['.'; empty as usize],
+                        None if unicode => vec![figurine(c)],
+                        None => vec![c],
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut files: Vec<char> = "abcdefgh".chars().collect();
+        let mut rank_numbers: Vec<usize> = (1..=8).rev().collect();
+        if perspective == pleco::Player::Black {
+            ranks.reverse();
+            ranks.iter_mut().for_each(|rank| rank.reverse());
+            files.reverse();
+            rank_numbers.reverse();
+        }
+        let mut rendered = String::new();
+        for (rank, number) in ranks.iter().zip(rank_numbers) {
+            rendered.push_str(&number.to_string());
+            for square in rank {
+                rendered.push(' ');
+                rendered.push(*square);
+            }
+            rendered.push('\n');
+        }
+        rendered.push(' ');
+        for file in files {
+            rendered.push(' ');
+            rendered.push(file);
+        }
+        rendered.push('\n');
+        rendered
+    }
+    /// FEN of the current position
+    pub fn current_fen(&self) -> String {
+        let castling = self.current_chess960_castling();
+        fen_with_castling(&self.compute_current_board(), castling.as_ref())
+    }
+    /// FEN of the initial board, with the castling rights of a Chess960 game
+    fn initial_fen(&self) -> String {
+        fen_with_castling(&self.initial_board, self.chess960_castling.as_ref())
+    }
+    /// Chess960 castling rights (see `ChessGameBuilder::with_fen`) of the initial position and
+    /// after each half-move, `None` for games pleco castles in
+    fn chess960_castling_per_ply(&self) -> Option<Vec<String>> {
+        let mut castling = self.chess960_castling.clone();
+        let mut board = self.initial_board.clone();
+        let mut per_ply = vec![castling.clone()?];
+        for mov in self.moves.iter() {
+            apply_game_move_with_castling(&mut board, &mut castling, mov);
+            per_ply.push(castling.clone()?);
+        }
+        Some(per_ply)
+    }
+    fn current_chess960_castling(&self) -> Option<String> {
+        self.chess960_castling_per_ply()?.pop()
+    }
+    /// FEN of the position after the first `turn` half-moves. `turn` is clamped to the
+    /// number of moves played, so any larger value gives the current position. The halfmove
+    /// clock and fullmove number are counted over the replayed moves from the initial FEN's.
+    pub fn fen_at_turn(&self, turn: u16) -> String {
+        let turn = (turn as usize).min(self.moves.len());
+        let castling = self.chess960_castling_per_ply();
+        let fen = fen_with_castling(
+            &self.compute_board_at_turn(turn as u16),
+            castling.as_ref().map(|castling| &castling[turn]),
+        );
+        let position: Vec<&str> = fen.split_whitespace().take(4).collect();
+        let black_moves = (0..turn)
+            .filter(|ply| self.player_at_ply(*ply) == pleco::Player::Black)
+            .count() as u16;
+        format!(
+            "{} {} {}",
+            position.join(" "),
+            self.halfmove_clocks()[turn],
+            fen_field(&self.initial_board, 5).unwrap_or(1) + black_moves
+        )
+    }
+    /// Number of the current full move as in the FEN, starting at 1 and increasing after
+    /// each of black's moves
+    pub fn full_move_number(&self) -> u16 {
+        fen_field(&self.compute_current_board(), 5).unwrap_or(1)
+    }
     pub fn is_move_legal(&self, mov: &Move) -> bool {
+        if mov.validate_uci().is_err() {
+            return false;
+        }
         let mut board = self.compute_current_board();
-        let is_legal = board.apply_uci_move(&mov.uci_move);
+        let mut castling = self.current_chess960_castling();
+        let is_legal = play_legal_move(&mut board, &mut castling, &mov.uci_move, 0).is_some();
         return is_legal;
     }
-    pub fn play_move(mut self, mov: Move) -> Result<ChessGame, Error> {
-        if self.is_move_legal(&mov) {
-            self.moves.push(mov);
+    /// Whether no more moves can be played: the settled `result` is decided, other than by a
+    /// draw that only gives a right to claim it
+    fn has_ended(&self) -> bool {
+        !matches!(
+            self.settled_result(),
+            GameResult::Ongoing
+                | GameResult::Draw(DrawReason::Threefold)
+                | GameResult::Draw(DrawReason::FiftyMove)
+        )
+    }
+    /// Ends the game with `player` resigning. A game that has already ended is unchanged.
+    pub fn resign(mut self, player: pleco::Player) -> ChessGame {
+        if !self.has_ended() {
+            self.result_override = Some(match player {
+                pleco::Player::White => GameResult::BlackWins,
+                pleco::Player::Black => GameResult::WhiteWins,
+            });
+        }
+        self
+    }
+    /// Ends the game in a draw both players agreed to. A game that has already ended is
+    /// unchanged.
+    pub fn agree_draw(mut self) -> ChessGame {
+        if !self.has_ended() {
+            self.result_override = Some(GameResult::Draw(DrawReason::Agreement));
+        }
+        self
+    }
+    /// Plays `mov` if it is legal and the game has not ended. Threefold repetition and the
+    /// fifty-move rule only give a right to claim a draw, so play may continue past them;
+    /// any other `result` fails with `ChessError::GameOver`. Clocks are judged on the moves
+    /// already played, not the wall clock.
+    pub fn play_move(self, mov: Move) -> Result<ChessGame, ChessError> {
+        self.play_move_at(mov, current_millis())
+    }
+    /// Same as `play_move`, with `now_millis` (milliseconds since the epoch) as the current
+    /// time. When the game was built `with_auto_first_move_time`, a first move without a
+    /// `time_taken` (0) is charged the time elapsed since the game started.
+    pub fn play_move_at(mut self, mut mov: Move, now_millis: u64) -> Result<ChessGame, ChessError> {
+        mov.uci_move = normalize_uci_move(mov.uci_move);
+        mov.validate_uci()?;
+        if self.has_ended() {
+            return Err(ChessError::GameOver);
+        }
+        if self.auto_first_move_time && self.moves.is_empty() && mov.time_taken == 0 {
+            mov.time_taken = self.compute_move_time_at(now_millis);
+        }
+        let mut board = self.compute_current_board();
+        let mut castling = self.current_chess960_castling();
+        match play_legal_move(&mut board, &mut castling, &mov.uci_move, mov.time_taken) {
+            Some(played) => {
+                mov.bit_move = played.bit_move;
+                self.moves.push(mov);
+                Ok(self)
+            }
+            None => Err(ChessError::IllegalMove { uci: mov.uci_move }),
+        }
+    }
+    /// Plays a move given in SAN, e.g. "Nf3", "exd5", "O-O" or "e8=Q", through `play_move`.
+    /// SAN matching no legal move or more than one fails with `IllegalSan` or `AmbiguousSan`.
+    pub fn play_san(self, san: &str, time_taken: u32) -> Result<ChessGame, ChessError> {
+        let castling = self.current_chess960_castling();
+        let candidates =
+            san_candidates_uci(&self.compute_current_board(), castling.as_deref(), san);
+        match candidates.len() {
+            1 => self.play_move(Move::new(candidates[0].clone(), time_taken)),
+            0 => Err(ChessError::IllegalSan(String::from(san))),
+            _ => Err(ChessError::AmbiguousSan(String::from(san))),
+        }
+    }
+    pub fn undo_move(mut self) -> Result<ChessGame, ChessError> {
+        if self.moves.pop().is_some() {
             Ok(self)
         } else {
-            Err(Error::new(
-                ErrorKind::Other,
-                "Tried playing an illegal move",
-            ))
+            Err(ChessError::NoMovesToUndo)
         }
     }
-    pub fn undo_move(mut self) -> Result<ChessGame, Error> {
-        if let Some(mov) = self.moves.pop() {
-            let mut board = self.compute_current_board();
-            board.undo_move();
-            Ok(self)
+    /// The player who made the move at index `ply` of the move list. Turns strictly
+    /// alternate from the initial board's side to move, so no board has to be replayed.
+    fn player_at_ply(&self, ply: usize) -> pleco::Player {
+        if ply.is_multiple_of(2) {
+            self.initial_board.turn()
         } else {
-            Err(Error::new(
-                ErrorKind::Other,
-                "Tried undoing a move when there are no moves to undo",
-            ))
+            self.initial_board.turn().other_player()
         }
     }
     ///Gives time taken by all white moves without increment
     pub fn compute_white_moves_pure_time(&self) -> u32 {
         let mut elapsed_time = 0;
-        for (mut turn, mov) in self.moves.iter().enumerate() {
-            turn += 1;
-            let turn_board = self.compute_board_at_turn(turn as u16);
-            if turn_board.turn() == pleco::Player::Black {
+        for (ply, mov) in self.moves.iter().enumerate() {
+            if self.player_at_ply(ply) == pleco::Player::White {
                 elapsed_time += mov.time_taken;
             }
         }
@@ -215,10 +711,8 @@ impl ChessGame {
     ///Gives time taken by all black moves without increment
     pub fn compute_black_moves_pure_time(&self) -> u32 {
         let mut elapsed_time = 0;
-        for (mut turn, mov) in self.moves.iter().enumerate() {
-            turn += 1;
-            let turn_board = self.compute_board_at_turn(turn as u16);
-            if turn_board.turn() == pleco::Player::Black {
+        for (ply, mov) in self.moves.iter().enumerate() {
+            if self.player_at_ply(ply) == pleco::Player::Black {
                 elapsed_time += mov.time_taken;
             }
         }
@@ -226,37 +720,64 @@ impl ChessGame {
     }
     pub fn compute_white_moves_time_with_increment(&self) -> u32 {
         let mut elapsed_time = 0;
-        for (mut turn, mov) in self.moves.iter().enumerate() {
-            turn += 1;
-            let turn_board = self.compute_board_at_turn(turn as u16);
-            if turn_board.turn() == pleco::Player::Black {
-                elapsed_time += mov.time_taken;
-                if elapsed_time >= self.increment {
-                    elapsed_time -= self.increment;
-                } else {
-                    elapsed_time = 0;
-                }
+        let mut move_number = 0;
+        for (ply, mov) in self.moves.iter().enumerate() {
+            if self.player_at_ply(ply) == pleco::Player::White {
+                move_number += 1;
+                elapsed_time = self
+                    .time_control_for_move(move_number)
+                    .settle(elapsed_time, mov.time_taken);
             }
         }
         elapsed_time
     }
     pub fn compute_black_moves_time_with_increment(&self) -> u32 {
         let mut elapsed_time = 0;
-        for (mut turn, mov) in self.moves.iter().enumerate() {
-            turn += 1;
-            let turn_board = self.compute_board_at_turn(turn as u16);
-            if turn_board.turn() == pleco::Player::White {
-                elapsed_time += mov.time_taken;
-                if elapsed_time >= self.increment {
-                    elapsed_time -= self.increment;
-                } else {
-                    elapsed_time = 0;
-                }
+        let mut move_number = 0;
+        for (ply, mov) in self.moves.iter().enumerate() {
+            if self.player_at_ply(ply) == pleco::Player::Black {
+                move_number += 1;
+                elapsed_time = self
+                    .time_control_for_move(move_number)
+                    .settle(elapsed_time, mov.time_taken);
             }
         }
         elapsed_time
     }
 
+    /// Mean `time_taken` of `player`'s moves, `None` before their first move
+    pub fn average_move_time(&self, player: pleco::Player) -> Option<u32> {
+        let times: Vec<u32> = self
+            .player_move_times(player)
+            .map(|(_, time)| time)
+            .collect();
+        if times.is_empty() {
+            return None;
+        }
+        Some((times.iter().map(|time| *time as u64).sum::<u64>() / times.len() as u64) as u32)
+    }
+    /// `player`'s longest move as its index in the move list and `time_taken`, the earliest
+    /// on ties. `None` before their first move.
+    pub fn slowest_move(&self, player: pleco::Player) -> Option<(usize, u32)> {
+        self.player_move_times(player)
+            .rev()
+            .max_by_key(|(_, time)| *time)
+    }
+    /// `player`'s quickest move as its index in the move list and `time_taken`, the
+    /// earliest on ties. `None` before their first move.
+    pub fn fastest_move(&self, player: pleco::Player) -> Option<(usize, u32)> {
+        self.player_move_times(player).min_by_key(|(_, time)| *time)
+    }
+    fn player_move_times(
+        &self,
+        player: pleco::Player,
+    ) -> impl DoubleEndedIterator<Item = (usize, u32)> + '_ {
+        self.moves
+            .iter()
+            .enumerate()
+            .filter(move |(ply, _)| self.player_at_ply(*ply) == player)
+            .map(|(ply, mov)| (ply, mov.time_taken))
+    }
     pub fn compute_total_moves_pure_time(&self) -> u32 {
         let mut elapsed_time = 0;
         for mov in self.moves.iter() {
@@ -266,25 +787,25 @@ impl ChessGame {
     }
     pub fn compute_total_move_time_with_increment(&self) -> u32 {
         let mut elapsed_time = 0;
-        for mov in self.moves.iter() {
-            elapsed_time += mov.time_taken;
-            if elapsed_time >= self.increment {
-                elapsed_time -= self.increment;
-            } else {
-                elapsed_time = 0;
-            }
+        for (index, mov) in self.moves.iter().enumerate() {
+            elapsed_time = self
+                .time_control_for_move(index / 2 + 1)
+                .settle(elapsed_time, mov.time_taken);
         }
         elapsed_time
     }
     /// Returns the time that has been used for the current move
     pub fn compute_current_move_time(&self) -> u32 {
-        let mut time_since_first_move = self.compute_total_moves_pure_time();
-        let now = SystemTime::now();
-        let current_time = now
-            .duration_since(std::time::UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis() as u32;
-        current_time - (self.start_time + time_since_first_move)
+        self.compute_move_time_at(current_millis())
+    }
+    /// Time used for the current move at the instant `now_millis` (milliseconds since the epoch)
+    fn compute_move_time_at(&self, now_millis: u64) -> u32 {
+        let time_since_first_move = self.compute_total_moves_pure_time();
+        // A start in the future (clock skew, tampered saves) counts as no time spent instead
+        // of underflowing
+        let elapsed =
+            now_millis.saturating_sub(self.start_time.saturating_add(time_since_first_move as u64));
+        elapsed.min(u32::MAX as u64) as u32
     }
     pub fn compute_total_elapsed_time(&self) -> u32 {
         let black_time = self.compute_black_moves_pure_time();
@@ -295,132 +816,5899 @@ impl ChessGame {
     }
     /// Returns the time that has been used by the white player FROM THEIR CLOCK TIME
     pub fn compute_white_used_time(&self) -> u32 {
-        let white_moves_time = self.compute_white_moves_time_with_increment();
-        let current_move_time = self.compute_current_move_time();
-        let turn = self.compute_current_board().turn();
-        match turn {
-            pleco::Player::White => white_moves_time + current_move_time,
-            pleco::Player::Black => white_moves_time,
-        }
+        self.compute_used_time_at(pleco::Player::White, current_millis())
     }
     /// Returns the time that has been used by the black player FROM THEIR CLOCK TIME
     pub fn compute_black_used_time(&self) -> u32 {
-        let black_moves_time = self.compute_black_moves_time_with_increment();
-        let current_move_time = self.compute_current_move_time();
-        println!("{} e {}", black_moves_time, current_move_time);
-        let turn = self.compute_current_board().turn();
-        match turn {
-            pleco::Player::White => black_moves_time,
-            pleco::Player::Black => black_moves_time + current_move_time,
+        self.compute_used_time_at(pleco::Player::Black, current_millis())
+    }
+    /// Time used by `player` from their clock at the instant `now_millis`, including the
+    /// running move if it is their turn
+    fn compute_used_time_at(&self, player: pleco::Player, now_millis: u64) -> u32 {
+        let moves_time = match player {
+            pleco::Player::White => self.compute_white_moves_time_with_increment(),
+            pleco::Player::Black => self.compute_black_moves_time_with_increment(),
+        };
+        if self.compute_current_board().turn() == player {
+            moves_time
+                + self
+                    .time_control
+                    .running_time(self.compute_move_time_at(now_millis))
+        } else {
+            moves_time
+        }
+    }
+    /// Time left on `player`'s clock at the instant `now_millis` (milliseconds since the
+    /// epoch): their settled move times with increment, minus the running move when it is
+    /// their turn. Negative once flagged. This is what `clock_display` shows per player.
+    pub fn player_clock(&self, player: pleco::Player, now_millis: u64) -> i64 {
+        self.clock_limit(player) as i64 - self.compute_used_time_at(player, now_millis) as i64
+    }
+    /// White's projected clock minus black's after `remaining_moves` more half-moves, the
+    /// side to move playing first. Each projected move is credited the Fischer increment of
+    /// its stage, and the base time of any stage it completes, assuming no further thinking
+    /// time. Positive values favour white.
+    pub fn projected_time_advantage(&self, remaining_moves: u32) -> i64 {
+        self.projected_time_advantage_at(remaining_moves, current_millis())
+    }
+    fn projected_time_advantage_at(&self, remaining_moves: u32, now_millis: u64) -> i64 {
+        let to_move = self.player_at_ply(self.moves.len());
+        let projected_clock = |player: pleco::Player| -> i64 {
+            let upcoming = if player == to_move {
+                (remaining_moves as usize).div_ceil(2)
+            } else {
+                remaining_moves as usize / 2
+            };
+            self.player_clock(player, now_millis)
+                + self.upcoming_credit(player, upcoming, TimeControl::increment)
+        };
+        projected_clock(pleco::Player::White) - projected_clock(pleco::Player::Black)
+    }
+    /// Milliseconds `player` can spend on each of their next `assumed_remaining_moves` moves
+    /// without flagging, counting the increment or delay of each of them. Zero assumed moves
+    /// is treated as one, i.e. the whole remaining time.
+    pub fn time_budget_per_move(&self, player: pleco::Player, assumed_remaining_moves: u32) -> i64 {
+        self.time_budget_per_move_at(player, assumed_remaining_moves, current_millis())
+    }
+    fn time_budget_per_move_at(
+        &self,
+        player: pleco::Player,
+        assumed_remaining_moves: u32,
+        now_millis: u64,
+    ) -> i64 {
+        let moves = assumed_remaining_moves.max(1);
+        let budget = self.player_clock(player, now_millis)
+            + self.upcoming_credit(player, moves as usize, TimeControl::per_move_credit);
+        budget / moves as i64
+    }
+    /// Time credited to `player` over their next `moves` moves: `credit` of the time control
+    /// of each of them, plus the base time of every stage those moves complete
+    fn upcoming_credit(
+        &self,
+        player: pleco::Player,
+        moves: usize,
+        credit: impl Fn(TimeControl) -> u32,
+    ) -> i64 {
+        let played = self.moves_played_by(player);
+        let per_move: i64 = (played + 1..=played + moves)
+            .map(|move_number| credit(self.time_control_for_move(move_number)) as i64)
+            .sum();
+        per_move + self.stage_time_added(played + moves) as i64
+            - self.stage_time_added(played) as i64
+    }
+    /// The moving player and their remaining clock time right after each half-move, in a
+    /// single pass. The time control is applied the same way as in the
+    /// `*_time_with_increment` methods, so a player's used time never drops below zero.
+    pub fn remaining_time_annotations(&self) -> Vec<(pleco::Player, i64)> {
+        let mut white_used: u32 = 0;
+        let mut black_used: u32 = 0;
+        let mut annotations = Vec::with_capacity(self.moves.len());
+        for (index, mov) in self.moves.iter().enumerate() {
+            let player = self.player_at_ply(index);
+            let used = match player {
+                pleco::Player::White => &mut white_used,
+                pleco::Player::Black => &mut black_used,
+            };
+            let move_number = index / 2 + 1;
+            *used = self
+                .time_control_for_move(move_number)
+                .settle(*used, mov.time_taken);
+            let limit = self.time_limit_for(player) + self.stage_time_added(move_number);
+            annotations.push((player, limit as i64 - *used as i64));
         }
+        annotations
+    }
+    /// Both clocks as a scoreboard shows them, white first: "M:SS", or "H:MM:SS" from one
+    /// hour up. Partial seconds are dropped and a flagged clock shows "0:00".
+    pub fn clock_display(&self, now_millis: u64) -> (String, String) {
+        (
+            format_clock(self.player_clock(pleco::Player::White, now_millis)),
+            format_clock(self.player_clock(pleco::Player::Black, now_millis)),
+        )
     }
 
+    /// Increment the side to move is credited once their current move is played, from the
+    /// stage that move belongs to, plus the base time of the next stage if it completes one:
+    /// the same amount `remaining_time_annotations` adds back. 0 when untimed, game over or
+    /// under a delay, which depends on how long the move takes.
+    pub fn pending_increment(&self) -> u32 {
+        if !self.is_timed() || self.outcome() != GameResult::Ongoing {
+            return 0;
+        }
+        let to_move = self.player_at_ply(self.moves.len());
+        self.upcoming_credit(to_move, 1, TimeControl::increment) as u32
+    }
+    /// Milliseconds left on white's clock now, negative once flagged
+    pub fn white_time_remaining(&self) -> i64 {
+        self.player_clock(pleco::Player::White, current_millis())
+    }
+    /// Milliseconds left on black's clock now, negative once flagged
+    pub fn black_time_remaining(&self) -> i64 {
+        self.player_clock(pleco::Player::Black, current_millis())
+    }
+    /// Whether white's clock has reached zero
     pub fn is_white_time_over(&self) -> bool {
-        let elapsed_time = self.compute_white_used_time();
-        elapsed_time > self.time_limit
+        self.white_time_remaining() <= 0
     }
+    /// Whether black's clock has reached zero
     pub fn is_black_time_over(&self) -> bool {
-        let elapsed_time = self.compute_black_used_time();
-        elapsed_time > self.time_limit
+        self.black_time_remaining() <= 0
+    }
+    /// The player whose clock has run out, if any, regardless of how the result is scored.
+    /// Untimed games never flag.
+    pub fn flagged_player(&self) -> Option<pleco::Player> {
+        if !self.is_timed() {
+            None
+        } else if self.is_white_time_over() {
+            Some(pleco::Player::White)
+        } else if self.is_black_time_over() {
+            Some(pleco::Player::Black)
+        } else {
+            None
+        }
+    }
+    /// The player whose turn it is in the current position
+    pub fn side_to_move(&self) -> pleco::Player {
+        self.compute_current_board().turn()
+    }
+    /// Whether the side to move is in check, checkmate included
+    pub fn is_check(&self) -> bool {
+        self.compute_current_board().in_check()
+    }
+    /// Whether the side to move was in check after the first `turn` half-moves
+    pub fn is_check_at_turn(&self, turn: u16) -> bool {
+        self.compute_board_at_turn(turn).in_check()
     }
     pub fn is_checkmate(&self) -> bool {
         let board = self.compute_current_board();
         board.checkmate()
     }
-}
-
-pub struct ChessGameBuilder {
-    initial_board: Board,
-    moves: Vec<Move>,
-    time_limit: u32, // in milliseconds
-    increment: u32,  // in milliseconds
-}
-// Get Time since epoch in miliseconds
-// let now = SystemTime::now();
-// let since_the_epoch = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
-// println!("Time since the epoch: {:?}", since_the_epoch);
-impl ChessGameBuilder {
-    pub fn new() -> ChessGameBuilder {
-        ChessGameBuilder {
-            initial_board: Board::start_pos(),
-            moves: Vec::new(),
-            time_limit: 0,
-            increment: 0,
+    /// Whether the side to move has no legal moves while not in check
+    pub fn is_stalemate(&self) -> bool {
+        let board = self.compute_current_board();
+        !board.in_check() && board.generate_moves().is_empty()
+    }
+    /// Returns the state of the game: a resignation or agreed draw, otherwise in priority
+    /// order:
+    /// 1. checkmate
+    /// 2. (when enabled) an automatic draw by insufficient material
+    /// 3. the side to move having run out of time
+    /// 4. stalemate
+    /// 5. the side that just moved having run out of time
+    /// 6. threefold repetition, then the fifty-move rule, while a draw can be claimed for
+    ///    them (see `can_claim_draw`)
+    ///
+    /// So a flagged clock beats a stalemate only if the side to move is the one who flagged.
+    /// Clocks are only considered when the game has a time limit.
+    pub fn result(&self) -> GameResult {
+        self.result_with_flags(|player| match player {
+            pleco::Player::White => self.is_white_time_over(),
+            pleco::Player::Black => self.is_black_time_over(),
+        })
+    }
+    /// `result` counting only the time of moves already played, without the running move,
+    /// so it does not depend on the wall clock
+    fn settled_result(&self) -> GameResult {
+        self.result_with_flags(|player| {
+            let used = match player {
+                pleco::Player::White => self.compute_white_moves_time_with_increment(),
+                pleco::Player::Black => self.compute_black_moves_time_with_increment(),
+            };
+            used >= self.clock_limit(player)
+        })
+    }
+    fn result_with_flags(&self, is_time_over: impl Fn(pleco::Player) -> bool) -> GameResult {
+        if let Some(result) = self.result_override {
+            return result;
+        }
+        let board = self.compute_current_board();
+        if board.checkmate() {
+            return match board.turn() {
+                pleco::Player::White => GameResult::BlackWins,
+                pleco::Player::Black => GameResult::WhiteWins,
+            };
+        }
+        if self.auto_draw_on_insufficient_material && is_insufficient_material(&board) {
+            return GameResult::Draw(DrawReason::InsufficientMaterial);
+        }
+        let is_flagged = |player: pleco::Player| self.is_timed() && is_time_over(player);
+        let to_move = board.turn();
+        if is_flagged(to_move) {
+            return self.timeout_result(&board, to_move);
+        }
+        if !board.in_check() && board.generate_moves().is_empty() {
+            return GameResult::Draw(DrawReason::Stalemate);
+        }
+        if is_flagged(to_move.other_player()) {
+            return self.timeout_result(&board, to_move.other_player());
+        }
+        // Play may continue past a repetition or the fifty-move rule, so they only stand as
+        // the result while a draw can be claimed
+        match self.can_claim_draw() {
+            Some(reason) => GameResult::Draw(reason),
+            None => GameResult::Ongoing,
         }
     }
-    pub fn with_initial_board(mut self, board: Board) -> ChessGameBuilder {
-        self.initial_board = board;
-        self
+    /// Same as `result`
+    pub fn outcome(&self) -> GameResult {
+        self.result()
     }
-    pub fn with_time_limit(mut self, time_limit: u32) -> ChessGameBuilder {
-        self.time_limit = time_limit;
-        self
+    /// Tournament score of a finished game for `player`: 1.0 for a win, 0.5 for a draw and
+    /// 0.0 for a loss, or `None` while the game is ongoing
+    pub fn score_for(&self, player: pleco::Player) -> Option<f64> {
+        match (self.outcome(), player) {
+            (GameResult::Ongoing, _) => None,
+            (GameResult::Draw(_), _) => Some(0.5),
+            (GameResult::WhiteWins, pleco::Player::White)
+            | (GameResult::BlackWins, pleco::Player::Black) => Some(1.0),
+            (GameResult::WhiteWins, pleco::Player::Black)
+            | (GameResult::BlackWins, pleco::Player::White) => Some(0.0),
+        }
     }
-    pub fn with_increment(mut self, increment: u32) -> ChessGameBuilder {
-        self.increment = increment;
-        self
+    /// Whether the game was decided by checkmate or resignation within 25 moves, counting
+    /// moves from the last one played. Wins on time do not count.
+    pub fn is_miniature(&self) -> bool {
+        if self.moves.is_empty() {
+            return false;
+        }
+        let board = self.compute_current_board();
+        let last_move_number = match board.turn() {
+            pleco::Player::White => self.full_move_number().saturating_sub(1),
+            pleco::Player::Black => self.full_move_number(),
+        };
+        let resigned = matches!(
+            self.result_override,
+            Some(GameResult::WhiteWins | GameResult::BlackWins)
+        );
+        (board.checkmate() || resigned) && last_move_number <= 25
     }
-    pub fn build(self) -> ChessGame {
-        let now = SystemTime::now();
-        ChessGame {
-            initial_board: self.initial_board,
-            moves: self.moves,
-            start_time: now
-                .duration_since(std::time::UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_millis() as u32,
-            time_limit: self.time_limit,
-            increment: self.increment,
+    fn is_timed(&self) -> bool {
+        self.time_limit_for(pleco::Player::White) > 0
+            || self.time_limit_for(pleco::Player::Black) > 0
+    }
+    /// Base time of `player` in milliseconds: `time_limit`, unless black was given a
+    /// different one with `with_black_time_limit`
+    pub fn time_limit_for(&self, player: pleco::Player) -> u32 {
+        match player {
+            pleco::Player::White => self.time_limit,
+            pleco::Player::Black => self.black_time_limit.unwrap_or(self.time_limit),
         }
     }
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Move {
-    uci_move: String,
-    time_taken: u32, // in milliseconds
-}
-impl Move {
-    pub fn new(uci_move: String, time_taken: u32) -> Move {
-        Move {
-            uci_move,
-            time_taken,
+    /// Base time of `player` plus the time added by the stages they have reached
+    fn clock_limit(&self, player: pleco::Player) -> u32 {
+        self.time_limit_for(player) + self.stage_time_added(self.moves_played_by(player))
+    }
+    /// Number of moves `player` has made
+    fn moves_played_by(&self, player: pleco::Player) -> usize {
+        (0..self.moves.len())
+            .filter(|ply| self.player_at_ply(*ply) == player)
+            .count()
+    }
+    /// Time added by the stages after the first once a player has made `moves_played`
+    /// moves. The base time of a stage is added when the previous one's moves are done.
+    fn stage_time_added(&self, moves_played: usize) -> u32 {
+        let mut stage_end = 0;
+        let mut added = 0;
+        for pair in self.time_control_stages.windows(2) {
+            let Some(moves) = pair[0].moves else {
+                break;
+            };
+            stage_end += moves as usize;
+            if moves_played < stage_end {
+                break;
+            }
+            added += pair[1].base_time;
         }
+        added
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::chess::*;
-    use pleco::Board;
-
-    #[test]
-    fn single_move_time_used() {
-        let mut game = ChessGameBuilder::new()
-            .with_time_limit(1000 * 60 * 3)
-            .with_increment(10)
-            .build();
-
-        let my_move = Move::new(String::from("e2e4"), 1000);
-        game = game.play_move(my_move).unwrap();
-        game.start_time -= 1000;
-        assert_eq!(game.compute_white_used_time(), 990);
-        assert_eq!(game.compute_black_used_time(), 0);
+    /// Time control of a player's `move_number`th move (from 1): the increment of the stage
+    /// it belongs to, the last stage covering every move after the others, or the game's
+    /// time control without stages
+    fn time_control_for_move(&self, move_number: usize) -> TimeControl {
+        let mut stage_end = 0;
+        for stage in self.time_control_stages.iter() {
+            match stage.moves {
+                Some(moves) if move_number > stage_end + moves as usize => {
+                    stage_end += moves as usize;
+                }
+                _ => return TimeControl::from_increment(stage.increment),
+            }
+        }
+        match self.time_control_stages.last() {
+            Some(stage) => TimeControl::from_increment(stage.increment),
+            None => self.time_control,
+        }
     }
-    #[test]
-    fn multiple_move_time_elapsed() {
-        let mut game = ChessGameBuilder::new()
-            .with_time_limit(1000 * 60 * 3)
-            .with_increment(10)
-            .build();
-        let my_move = Move::new(String::from("e2e4"), 1000);
-        game = game.play_move(my_move).unwrap();
-        let my_move = Move::new(String::from("a7a6"), 1000);
-        game = game.play_move(my_move).unwrap();
-        let my_move = Move::new(String::from("g1h3"), 500);
-        game = game.play_move(my_move).unwrap();
-        game.start_time -= 2500;
-        assert_eq!(game.compute_white_used_time(), 1480);
-        assert_eq!(game.compute_black_used_time(), 990);
+    /// Larger base time divided by the smaller one, e.g. 2.0 for 5 minutes against 2:30,
+    /// for labelling odds games. 1.0 for equal or untimed clocks; when only one side has a
+    /// base time the handicap is unbounded and `f64::INFINITY` is returned.
+    pub fn time_handicap_ratio(&self) -> f64 {
+        let white = self.time_limit_for(pleco::Player::White);
+        let black = self.time_limit_for(pleco::Player::Black);
+        let (smaller, larger) = (white.min(black), white.max(black));
+        if larger == smaller {
+            1.0
+        } else if smaller == 0 {
+            f64::INFINITY
+        } else {
+            larger as f64 / smaller as f64
+        }
+    }
+    /// Result of `flagged` running out of time. Under FIDE rules this is a draw when the
+    /// opponent is left with a lone king, since they can no longer checkmate.
+    fn timeout_result(&self, board: &Board, flagged: pleco::Player) -> GameResult {
+        let opponent = flagged.other_player();
+        if self.timeout_insufficient_material_is_draw
+            && board.get_occupied_player(opponent).count_bits() == 1
+        {
+            return GameResult::Draw(DrawReason::TimeoutVsInsufficientMaterial);
+        }
+        match opponent {
+            pleco::Player::White => GameResult::WhiteWins,
+            pleco::Player::Black => GameResult::BlackWins,
+        }
+    }
+    /// Number of leaf positions `depth` plies below the current position, for checking move
+    /// generation against known values (20, 400 and 8902 from the start position)
+    pub fn perft(&self, depth: u16) -> u64 {
+        perft_board(&mut self.compute_current_board(), depth)
+    }
+    /// `perft` split by root move: each legal move (UCI) with the leaf count below it
+    pub fn perft_divide(&self, depth: u16) -> Vec<(String, u64)> {
+        let mut board = self.compute_current_board();
+        let mut divide = Vec::new();
+        for mov in board.generate_moves().iter() {
+            board.apply_move(*mov);
+            divide.push((
+                mov.stringify(),
+                perft_board(&mut board, depth.saturating_sub(1)),
+            ));
+            board.undo_move();
+        }
+        divide
+    }
+    /// Engine evaluation of the current position in centipawns, positive favouring white.
+    /// `depth` is capped at `MAX_SEARCH_DEPTH`; each extra ply multiplies the work by the
+    /// number of legal moves (roughly 30 in a middlegame).
+    pub fn evaluate(&self, depth: u16) -> i32 {
+        evaluate_board(&self.compute_current_board(), depth)
+    }
+    /// The side the engine considers winning by at least `cp_threshold` centipawns at the
+    /// given depth, or `None` while the game is still contested
+    pub fn is_likely_decided(&self, depth: u16, cp_threshold: i32) -> Option<pleco::Player> {
+        let evaluation = self.evaluate(depth);
+        if evaluation >= cp_threshold {
+            Some(pleco::Player::White)
+        } else if evaluation <= -cp_threshold {
+            Some(pleco::Player::Black)
+        } else {
+            None
+        }
+    }
+    /// Whether `uci` is the only legal move scoring within `margin_cp` centipawns of the best
+    /// one, as a puzzle solution should be. Every legal move is searched `depth` plies deep
+    /// (the move itself counting as the first), so this costs one search per legal move.
+    pub fn is_unique_best(&self, uci: &str, depth: u16, margin_cp: i32) -> Result<bool, Error> {
+        let board = self.compute_current_board();
+        let candidate = find_legal_move(&board, uci)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Not a legal move"))?;
+        let sign = match board.turn() {
+            pleco::Player::White => 1,
+            pleco::Player::Black => -1,
+        };
+        let scores: Vec<(BitMove, i32)> = board
+            .generate_moves()
+            .iter()
+            .map(|mov| {
+                let mut next = board.clone();
+                next.apply_move(*mov);
+                (*mov, sign * evaluate_board(&next, depth.saturating_sub(1)))
+            })
+            .collect();
+        let best = scores.iter().map(|(_, score)| *score).max().unwrap_or(0);
+        let mut contenders = scores
+            .iter()
+            .filter(|(_, score)| *score >= best - margin_cp);
+        Ok(matches!(
+            (contenders.next(), contenders.next()),
+            (Some((mov, _)), None) if *mov == candidate
+        ))
+    }
+    /// How sharp the current position is: the standard deviation, in centipawns, of the
+    /// scores of the best `COMPLEXITY_MOVES` legal moves, each searched to
+    /// `COMPLEXITY_DEPTH` plies after the move (scores are clamped to +-1000 so mates do not
+    /// dominate). 0 when there is at most one legal move or all the top moves are equivalent;
+    /// larger values mean the choice between the top moves matters.
+    pub fn complexity(&self) -> f64 {
+        let board = self.compute_current_board();
+        let sign = match board.turn() {
+            pleco::Player::White => 1,
+            pleco::Player::Black => -1,
+        };
+        let mut scores: Vec<f64> = board
+            .generate_moves()
+            .iter()
+            .map(|mov| {
+                let mut next = board.clone();
+                next.apply_move(*mov);
+                (sign * evaluate_board(&next, COMPLEXITY_DEPTH)).clamp(-1000, 1000) as f64
+            })
+            .collect();
+        scores.sort_by(|a, b| b.total_cmp(a));
+        scores.truncate(COMPLEXITY_MOVES);
+        if scores.len() < 2 {
+            return 0.0;
+        }
+        let mean = scores.iter().sum::<f64>() / scores.len() as f64;
+        let variance = scores
+            .iter()
+            .map(|score| (score - mean).powi(2))
+            .sum::<f64>()
+            / scores.len() as f64;
+        variance.sqrt()
+    }
+    /// Engine evaluation after each half-move, starting with the initial position, so the
+    /// result has `moves.len() + 1` entries. Costs one `evaluate` per position.
+    pub fn eval_history(&self, depth: u16) -> Vec<i32> {
+        let mut board = self.initial_board.clone();
+        let mut history = vec![evaluate_board(&board, depth)];
+        for mov in self.moves.iter() {
+            apply_game_move(&mut board, mov); //Assumes all the previous moves were valid
+            history.push(evaluate_board(&board, depth));
+        }
+        history
+    }
+    /// Ply of the move causing the largest evaluation swing at the given depth, i.e. the
+    /// largest absolute difference between consecutive `eval_history` entries (the earliest
+    /// one on ties). Plies count from 0 as indices into `moves`, like in `analysis_iter`.
+    /// `None` when no moves have been played.
+    pub fn critical_moment(&self, depth: u16) -> Option<usize> {
+        let history = self.eval_history(depth);
+        let mut critical: Option<(usize, i32)> = None;
+        for (index, pair) in history.windows(2).enumerate() {
+            let swing = (pair[1] - pair[0]).abs();
+            if critical.is_none_or(|(_, largest)| swing > largest) {
+                critical = Some((index, swing));
+            }
+        }
+        critical.map(|(ply, _)| ply)
+    }
+    /// Lazily analyses the game one position at a time: for the position before each played
+    /// move, the move's ply (from 0, its index in `moves`), the `evaluate` score and the
+    /// engine's best move (UCI notation) at the given depth. Yields `moves.len()` items, each
+    /// costing about two searches.
+    pub fn analysis_iter(&self, depth: u16) -> impl Iterator<Item = (usize, i32, String)> + '_ {
+        let mut board = self.initial_board.clone();
+        self.moves.iter().enumerate().map(move |(ply, mov)| {
+            let evaluation = evaluate_board(&board, depth);
+            let best = best_move(&board, depth)
+                .map(|mov| mov.stringify())
+                .unwrap_or_default();
+            apply_game_move(&mut board, mov); //Assumes all the previous moves were valid
+            (ply, evaluation, best)
+        })
+    }
+    /// Earliest ply at which the current position was reached, comparing normalized FENs.
+    /// Returns 0 when the current position is the initial one or has not occurred before.
+    pub fn first_occurrence_ply(&self) -> usize {
+        let positions = self.position_keys();
+        let current_ply = positions.len() - 1;
+        let first_ply = positions
+            .iter()
+            .position(|position| *position == positions[current_ply])
+            .unwrap_or(0);
+        if first_ply == current_ply {
+            0
+        } else {
+            first_ply
+        }
+    }
+    /// Recognises a small, explicit set of theoretically drawn fortresses and returns false for
+    /// anything else. The covered set is:
+    /// - king and rook pawns (all on the same rook file) against a lone king
+    /// - the same with bishops that cannot control the promotion square ("wrong bishop")
+    ///
+    /// In both cases the defending king must be within one square of the promotion square.
+    pub fn is_known_fortress(&self) -> bool {
+        let board = self.compute_current_board();
+        is_rook_pawn_fortress(&board, pleco::Player::White)
+            || is_rook_pawn_fortress(&board, pleco::Player::Black)
+    }
+    /// Space advantage: the number of distinct squares in the opponent's half of the board
+    /// (ranks 5-8 for white, 1-4 for black) attacked by at least one of `player`'s pieces or
+    /// pawns in the current position. Occupied squares count as well.
+    pub fn space(&self, player: pleco::Player) -> i32 {
+        let board = self.compute_current_board();
+        let opponent_half = match player {
+            pleco::Player::White => 0xFFFF_FFFF_0000_0000u64,
+            pleco::Player::Black => 0x0000_0000_FFFF_FFFFu64,
+        };
+        (attack_map(&board, player) & opponent_half).count_ones() as i32
+    }
+    /// Number of pieces on the board, both colours, kings and pawns included
+    pub fn piece_count(&self) -> u8 {
+        self.compute_current_board().get_occupied().0.count_ones() as u8
+    }
+    /// First ply at which at most `max_pieces` pieces were left on the board, e.g. 7 for the
+    /// largest tablebases, counting the initial position as ply 0
+    pub fn entered_tablebase_at(&self, max_pieces: u8) -> Option<usize> {
+        let mut board = self.initial_board.clone();
+        let within = |board: &Board| board.get_occupied().0.count_ones() as u8 <= max_pieces;
+        if within(&board) {
+            return Some(0);
+        }
+        for (ply, mov) in self.moves.iter().enumerate() {
+            apply_game_move(&mut board, mov); //Assumes all the previous moves were valid
+            if within(&board) {
+                return Some(ply + 1);
+            }
+        }
+        None
+    }
+    /// Whether white and black, respectively, still have at least one queen
+    pub fn queens_on_board(&self) -> (bool, bool) {
+        let board = self.compute_current_board();
+        (
+            board.piece_bb(pleco::Player::White, pleco::PieceType::Q).0 != 0,
+            board.piece_bb(pleco::Player::Black, pleco::PieceType::Q).0 != 0,
+        )
+    }
+    /// Chebyshev distance between the two kings, i.e. how many king moves apart they are
+    pub fn king_distance(&self) -> u8 {
+        let board = self.compute_current_board();
+        square_distance(
+            board.king_sq(pleco::Player::White),
+            board.king_sq(pleco::Player::Black),
+        )
+    }
+    /// Endgame king activity for `player`, higher is better: 10 points per step closer to the
+    /// centre than the corners (`CENTER_DISTANCE`, up to 60) plus 5 points per step closer
+    /// than 7 to the nearest pawn of either colour, or to the enemy king when no pawns are left
+    pub fn king_activity(&self, player: pleco::Player) -> i32 {
+        let board = self.compute_current_board();
+        let king = board.king_sq(player);
+        let mut pawns = board.piece_bb_both_players(pleco::PieceType::P).0;
+        let mut nearest = if pawns == 0 {
+            square_distance(king, board.king_sq(player.other_player()))
+        } else {
+            7
+        };
+        while pawns != 0 {
+            let square = pleco::SQ(pawns.trailing_zeros() as u8);
+            pawns &= pawns - 1;
+            nearest = nearest.min(square_distance(king, square));
+        }
+        (6 - CENTER_DISTANCE[king.0 as usize] as i32) * 10 + (7 - nearest as i32) * 5
+    }
+    /// Whether `player` holds the opposition: the kings stand on the same file, rank or
+    /// diagonal with a single square between them and it is the opponent's turn to move
+    pub fn has_opposition(&self, player: pleco::Player) -> bool {
+        let board = self.compute_current_board();
+        let white_king = board.king_sq(pleco::Player::White);
+        let black_king = board.king_sq(pleco::Player::Black);
+        let file_distance = (white_king.0 % 8).abs_diff(black_king.0 % 8);
+        let rank_distance = (white_king.0 / 8).abs_diff(black_king.0 / 8);
+        let in_opposition = matches!((file_distance, rank_distance), (0, 2) | (2, 0) | (2, 2));
+        in_opposition && board.turn() != player
+    }
+    /// One game per legal move, with that move played (`time_taken` 0), for tree search.
+    /// Every child is a full clone of this game, so this allocates one move list per legal
+    /// move on top of replaying the game for each `play_move` legality check.
+    pub fn children(&self) -> Vec<ChessGame> {
+        self.legal_moves()
+            .into_iter()
+            .filter_map(|uci| self.clone().play_move(Move::new(uci, 0)).ok())
+            .collect()
+    }
+    /// SAN of the most recent move, with check and mate suffixes, or `None` before the first
+    /// move. Only replays up to the previous position instead of converting every move.
+    pub fn last_move_san(&self) -> Option<String> {
+        let last_move = self.moves.last()?;
+        let board = self.compute_board_at_turn(self.moves.len() as u16 - 1);
+        last_move.san_on(&board)
+    }
+    /// Whether a pawn moving from `from` to `to` (e.g. "e7", "e8") in the current position
+    /// must name a promotion piece, i.e. it lands on the last rank for its side. Fails when
+    /// either square is invalid or there is no pawn on `from`; the move's legality is not checked.
+    pub fn requires_promotion(&self, from: &str, to: &str) -> Result<bool, Error> {
+        let (from, to) = match (parse_square(from), parse_square(to)) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "Invalid square")),
+        };
+        let board = self.compute_current_board();
+        let from_bit = 1u64 << from.0;
+        let last_rank =
+            if board.piece_bb(pleco::Player::White, pleco::PieceType::P).0 & from_bit != 0 {
+                7
+            } else if board.piece_bb(pleco::Player::Black, pleco::PieceType::P).0 & from_bit != 0 {
+                0
+            } else {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "No pawn on the origin square",
+                ));
+            };
+        Ok(to.0 / 8 == last_rank)
+    }
+    /// Static evaluation in centipawns, positive favouring white, that blends a middlegame and
+    /// an endgame score (material plus piece-square tables) by game phase. The phase counts
+    /// the remaining non-pawn material (knight and bishop 1, rook 2, queen 4, 24 at the start,
+    /// capped there after promotions), and the result is
+    /// `(middlegame * phase + endgame * (24 - phase)) / 24`, so the endgame tables take over
+    /// gradually as pieces come off the board.
+    pub fn tapered_eval(&self) -> i32 {
+        tapered_score(&self.compute_current_board())
+    }
+    /// Why the castling move `uci` (e.g. "e1g1") is illegal in the current position, checked
+    /// in the order the variants are declared. Returns `None` when the move is legal or is not
+    /// a castling move of the side to move. Chess960 castles are the king taking its own rook,
+    /// e.g. "g1h1".
+    pub fn castling_illegality_reason(&self, uci: &str) -> Option<CastleError> {
+        let board = self.compute_current_board();
+        if let Some(castling) = self.current_chess960_castling() {
+            let squares = chess960_castle_squares(&board, uci)?;
+            return chess960_castle_error(&board, &castling, squares);
+        }
+        let player = board.turn();
+        let (rights, back_rank) = match player {
+            pleco::Player::White => (['K', 'Q'], "1"),
+            pleco::Player::Black => (['k', 'q'], "8"),
+        };
+        let (right, between, crossed, destination) = if uci == format!("e{0}g{0}", back_rank) {
+            (rights[0], vec!["f", "g"], "f", "g")
+        } else if uci == format!("e{0}c{0}", back_rank) {
+            (rights[1], vec!["d", "c", "b"], "d", "c")
+        } else {
+            return None;
+        };
+        let square_bit =
+            |file: &str| 1u64 << parse_square(&format!("{}{}", file, back_rank)).unwrap().0;
+        if board.king_sq(player).0 != parse_square(&format!("e{}", back_rank))?.0 {
+            return None;
+        }
+        let castling_field = board
+            .fen()
+            .split_whitespace()
+            .nth(2)
+            .unwrap_or("-")
+            .to_string();
+        let attacked = attack_map(&board, player.other_player());
+        if !castling_field.contains(right) {
+            Some(CastleError::NoRights)
+        } else if between
+            .iter()
+            .any(|file| board.get_occupied().0 & square_bit(file) != 0)
+        {
+            Some(CastleError::PathBlocked)
+        } else if board.in_check() {
+            Some(CastleError::KingInCheck)
+        } else if attacked & square_bit(crossed) != 0 {
+            Some(CastleError::PassesThroughCheck)
+        } else if attacked & square_bit(destination) != 0 {
+            Some(CastleError::LandsInCheck)
+        } else {
+            None
+        }
+    }
+    /// Whether `player` has a legal promoting move. For the side not to move, the current
+    /// position is checked as if it were their turn (without en passant), which is never the
+    /// case while the side to move is in check.
+    pub fn promotion_available(&self, player: pleco::Player) -> bool {
+        let board = self.compute_current_board();
+        let board = if board.turn() == player {
+            board
+        } else if board.in_check() {
+            return false;
+        } else {
+            let mut fields: Vec<String> =
+                board.fen().split_whitespace().map(String::from).collect();
+            fields[1] = String::from(match player {
+                pleco::Player::White => "w",
+                pleco::Player::Black => "b",
+            });
+            fields[3] = String::from("-");
+            match Board::from_fen(&fields.join(" ")) {
+                Ok(board) => board,
+                Err(_) => return false,
+            }
+        };
+        board.generate_moves().iter().any(|mov| mov.is_promo())
+    }
+    /// Every move in SAN, e.g. "Nbd2", "exd5", "O-O", "e8=Q" or "Qh4#", each converted
+    /// against the position before it
+    pub fn moves_san(&self) -> Vec<String> {
+        let mut board = self.initial_board.clone();
+        let mut moves = Vec::with_capacity(self.moves.len());
+        for mov in self.moves.iter() {
+            if let Some(san) = mov.san_on(&board) {
+                moves.push(san);
+                apply_game_move(&mut board, mov); //Assumes all the previous moves were valid
+            }
+        }
+        moves
+    }
+    /// What the most recent move did, `None` before the first move
+    pub fn last_move_info(&self) -> Option<MoveInfo> {
+        let last_move = self.moves.last()?;
+        let mut board = self.compute_board_at_turn(self.moves.len() as u16 - 1);
+        let san = last_move.san_on(&board)?;
+        // only a Chess960 castle has no `BitMove`
+        let bit_move = last_move.bit_move_on(&board);
+        apply_game_move(&mut board, last_move);
+        Some(MoveInfo {
+            san,
+            is_capture: bit_move.is_some_and(|mov| mov.is_capture()),
+            is_check: board.in_check(),
+            is_castle: bit_move.is_none_or(|mov| mov.is_castle()),
+            is_promotion: bit_move.is_some_and(|mov| mov.is_promo()),
+        })
+    }
+    /// UCI strings of every legal move in the current position, Chess960 castles last
+    pub fn legal_moves(&self) -> Vec<String> {
+        let board = self.compute_current_board();
+        let mut moves: Vec<String> = board
+            .generate_moves()
+            .iter()
+            .map(|mov| mov.stringify())
+            .collect();
+        if let Some(castling) = self.current_chess960_castling() {
+            moves.extend(chess960_castles(&board, &castling));
+        }
+        moves
+    }
+    /// `legal_moves` in SAN, in the same order
+    pub fn legal_moves_san(&self) -> Vec<String> {
+        let board = self.compute_current_board();
+        let mut moves: Vec<String> = board
+            .generate_moves()
+            .iter()
+            .map(|mov| move_to_san(&board, *mov))
+            .collect();
+        if let Some(castling) = self.current_chess960_castling() {
+            moves.extend(
+                chess960_castles(&board, &castling)
+                    .iter()
+                    .filter_map(|uci| chess960_castle_san(&board, uci)),
+            );
+        }
+        moves
+    }
+    /// The game as PGN: the Seven Tag Roster from the game's metadata (unknown values as "?",
+    /// the date as "????.??.??"), a `Variant` tag for Chess960 castling, `SetUp` and `FEN`
+    /// tags when the game does not start from the standard position, then the SAN movetext
+    /// with move numbers and each move's NAG and comment, wrapped below 80 columns and ending
+    /// with the `result` marker
+    pub fn to_pgn(&self) -> String {
+        let result = match self.result() {
+            GameResult::WhiteWins => "1-0",
+            GameResult::BlackWins => "0-1",
+            GameResult::Draw(_) => "1/2-1/2",
+            GameResult::Ongoing => "*",
+        };
+        let mut pgn = String::new();
+        let tag_value = |value: &Option<String>, unknown: &str| {
+            value
+                .as_deref()
+                .unwrap_or(unknown)
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+        };
+        for (tag, value) in [
+            ("Event", tag_value(&self.event, "?")),
+            ("Site", tag_value(&self.site, "?")),
+            ("Date", tag_value(&self.date, "????.??.??")),
+            ("Round", tag_value(&self.round, "?")),
+            ("White", tag_value(&self.white_name, "?")),
+            ("Black", tag_value(&self.black_name, "?")),
+            ("Result", String::from(result)),
+        ] {
+            pgn.push_str(&format!("[{} \"{}\"]\n", tag, value));
+        }
+        if self.chess960_castling.is_some() {
+            pgn.push_str("[Variant \"Chess960\"]\n");
+        }
+        let initial_fen = self.initial_fen();
+        if initial_fen != Board::start_pos().fen() {
+            pgn.push_str("[SetUp \"1\"]\n");
+            pgn.push_str(&format!("[FEN \"{}\"]\n", initial_fen));
+        }
+        pgn.push('\n');
+        let mut tokens = Vec::new();
+        let mut move_number = fen_field(&self.initial_board, 5).unwrap_or(1);
+        let mut player = self.initial_board.turn();
+        let mut after_comment = false;
+        for (index, (san, mov)) in self.moves_san().into_iter().zip(&self.moves).enumerate() {
+            match player {
+                pleco::Player::White => tokens.push(format!("{}.", move_number)),
+                pleco::Player::Black if index == 0 || after_comment => {
+                    tokens.push(format!("{}...", move_number))
+                }
+                pleco::Player::Black => {}
+            }
+            tokens.push(san);
+            if let Some(nag) = mov.nag {
+                tokens.push(format!("${}", nag));
+            }
+            after_comment = mov.comment.is_some();
+            if let Some(comment) = &mov.comment {
+                // a comment ends at the first '}', so it cannot contain one
+                tokens.push(format!("{{{}}}", comment.replace('}', ")")));
+            }
+            if player == pleco::Player::Black {
+                move_number += 1;
+            }
+            player = player.other_player();
+        }
+        tokens.push(String::from(result));
+        let mut line_length = 0;
+        for token in tokens {
+            if line_length > 0 && line_length + 1 + token.len() >= 80 {
+                pgn.push('\n');
+                line_length = 0;
+            } else if line_length > 0 {
+                pgn.push(' ');
+                line_length += 1;
+            }
+            line_length += token.len();
+            pgn.push_str(&token);
+        }
+        pgn.push('\n');
+        pgn
+    }
+    /// Reads a PGN game: the tag pairs (the Seven Tag Roster as metadata, `FEN` for the
+    /// initial board, read as by `ChessGameBuilder::with_fen`, and `TimeControl`, other tags
+    /// are ignored) followed by the SAN movetext. A move's `time_taken` is derived from the
+    /// `[%clk h:mm:ss]` comments as the drop of the mover's clock since their previous move
+    /// plus the `TimeControl` increment, the first move counting from the base time; moves
+    /// without a clock comment take 0. Tag pairs are optional. The `TimeControl` base time and
+    /// increment become the game's `time_limit` and time control; one whose seconds overflow
+    /// or whose increment is not a number is rejected.
+    pub fn from_pgn(pgn: &str) -> Result<ChessGame, ChessError> {
+        let mut initial_board = Board::start_pos();
+        let mut chess960_castling = None;
+        let mut base_time = None;
+        let mut increment = 0;
+        let mut movetext = String::new();
+        let mut metadata: [Option<String>; 6] = Default::default();
+        for line in pgn.lines() {
+            let line = line.trim();
+            let Some(tag) = line.strip_prefix('[') else {
+                movetext.push_str(line);
+                movetext.push('\n');
+                continue;
+            };
+            let (name, value) = tag
+                .trim_end_matches(']')
+                .split_once(' ')
+                .ok_or_else(|| ChessError::InvalidPgn(format!("Malformed tag pair: {}", line)))?;
+            let value = value.trim().trim_matches('"');
+            match name {
+                "FEN" => {
+                    (initial_board, chess960_castling) = board_from_fen(value).map_err(|_| {
+                        ChessError::InvalidPgn(format!("Invalid FEN tag: {}", value))
+                    })?;
+                    check_side_not_to_move(&initial_board)?;
+                }
+                "TimeControl" => {
+                    let invalid =
+                        || ChessError::InvalidPgn(format!("Invalid TimeControl tag: {}", value));
+                    let millis = |seconds: &str| {
+                        seconds
+                            .parse::<u32>()
+                            .ok()
+                            .and_then(|seconds| seconds.checked_mul(1000))
+                            .ok_or_else(invalid)
+                    };
+                    let (base, increment_seconds) = value.split_once('+').unwrap_or((value, "0"));
+                    // "?", "-" and the other non-numeric forms leave the base time unknown
+                    base_time = if !base.is_empty() && base.chars().all(|c| c.is_ascii_digit()) {
+                        Some(millis(base)?)
+                    } else {
+                        None
+                    };
+                    increment = millis(increment_seconds)?;
+                }
+                "White" | "Black" | "Event" | "Site" | "Date" | "Round" => {
+                    let index = ["White", "Black", "Event", "Site", "Date", "Round"]
+                        .iter()
+                        .position(|tag| *tag == name)
+                        .unwrap_or(0);
+                    let value = value.replace("\\\"", "\"").replace("\\\\", "\\");
+                    let is_unknown = value.chars().all(|c| c == '?' || c == '.');
+                    metadata[index] = Some(value).filter(|_| !is_unknown);
+                }
+                _ => {}
+            }
+        }
+        let mut game = ChessGameBuilder::new()
+            .with_initial_board(initial_board.clone())
+            .with_time_limit(base_time.unwrap_or(0))
+            .with_increment(increment)
+            .build();
+        [
+            game.white_name,
+            game.black_name,
+            game.event,
+            game.site,
+            game.date,
+            game.round,
+        ] = metadata;
+        game.chess960_castling = chess960_castling.clone();
+        let mut board = initial_board;
+        let mut castling = chess960_castling;
+        let mut last_clocks = [base_time, base_time];
+        for (san, clock_comment) in movetext_entries(&movetext) {
+            let clock = clock_comment
+                .map(|comment| parse_clock_comment(&comment))
+                .transpose()?;
+            let candidates = san_candidates_uci(&board, castling.as_deref(), &san);
+            let uci = match candidates.len() {
+                1 => &candidates[0],
+                0 => return Err(ChessError::IllegalSan(san)),
+                _ => return Err(ChessError::AmbiguousSan(san)),
+            };
+            let side = match board.turn() {
+                pleco::Player::White => 0,
+                pleco::Player::Black => 1,
+            };
+            let time_taken = match (last_clocks[side], clock) {
+                (Some(previous), Some(clock)) => {
+                    previous.saturating_add(increment).saturating_sub(clock)
+                }
+                _ => 0,
+            };
+            if clock.is_some() {
+                last_clocks[side] = clock;
+            }
+            let mov = play_legal_move(&mut board, &mut castling, uci, time_taken)
+                .ok_or(ChessError::IllegalSan(san))?;
+            game.moves.push(mov);
+        }
+        Ok(game)
+    }
+    /// SAN, UCI and `time_taken` of every move, in a single replay of the game
+    pub fn annotated_moves(&self) -> Vec<(String, String, u32)> {
+        let mut board = self.initial_board.clone();
+        let mut annotated = Vec::with_capacity(self.moves.len());
+        for mov in self.moves.iter() {
+            let san = mov.san_on(&board).unwrap_or_default();
+            apply_game_move(&mut board, mov); //Assumes all the previous moves were valid
+            annotated.push((san, mov.uci_move.clone(), mov.time_taken));
+        }
+        annotated
+    }
+    /// Centipawns `player` gained through promotions over the game: for each of their
+    /// promotions, the promoted piece's value minus a pawn's
+    pub fn promoted_material(&self, player: pleco::Player) -> i32 {
+        self.promotions()
+            .into_iter()
+            .filter(|(promoting_player, _)| *promoting_player == player)
+            .map(|(_, piece)| piece_value(piece) - piece_value(pleco::PieceType::P))
+            .sum()
+    }
+    /// Material on the board in pawns (1/3/3/5/9), white's minus black's
+    pub fn material_balance(&self) -> i32 {
+        let board = self.compute_current_board();
+        let material = |player: pleco::Player| -> i32 {
+            [
+                (pleco::PieceType::P, 1),
+                (pleco::PieceType::N, 3),
+                (pleco::PieceType::B, 3),
+                (pleco::PieceType::R, 5),
+                (pleco::PieceType::Q, 9),
+            ]
+            .iter()
+            .map(|(piece, value)| board.piece_bb(player, *piece).0.count_ones() as i32 * value)
+            .sum()
+        };
+        material(pleco::Player::White) - material(pleco::Player::Black)
+    }
+    /// White's captured pieces, then black's, from pawns to queens, found by comparing the
+    /// initial and current boards. A promoted pawn was not captured, and a captured promoted
+    /// piece counts as the piece it became.
+    pub fn captured_pieces(&self) -> (Vec<pleco::PieceType>, Vec<pleco::PieceType>) {
+        let initial = &self.initial_board;
+        let current = self.compute_current_board();
+        let promotions = self.promotions();
+        let captured = |player: pleco::Player| {
+            let mut captured = Vec::new();
+            for piece in [
+                pleco::PieceType::P,
+                pleco::PieceType::N,
+                pleco::PieceType::B,
+                pleco::PieceType::R,
+                pleco::PieceType::Q,
+            ] {
+                let count = |board: &Board| board.piece_bb(player, piece).0.count_ones() as usize;
+                let promoted = promotions
+                    .iter()
+                    .filter(|(promoting_player, promoted_piece)| {
+                        *promoting_player == player
+                            && (piece == pleco::PieceType::P || *promoted_piece == piece)
+                    })
+                    .count();
+                // a promotion removes a pawn and adds the promoted piece
+                let missing = if piece == pleco::PieceType::P {
+                    count(initial).saturating_sub(count(&current) + promoted)
+                } else {
+                    (count(initial) + promoted).saturating_sub(count(&current))
+                };
+                captured.extend(std::iter::repeat_n(piece, missing));
+            }
+            captured
+        };
+        (
+            captured(pleco::Player::White),
+            captured(pleco::Player::Black),
+        )
+    }
+    /// The player and promoted piece of every promotion played, in order
+    fn promotions(&self) -> Vec<(pleco::Player, pleco::PieceType)> {
+        let mut board = self.initial_board.clone();
+        let mut promotions = Vec::new();
+        for mov in self.moves.iter() {
+            if let Some(bit_move) = mov.bit_move_on(&board) {
+                if bit_move.is_promo() {
+                    promotions.push((board.turn(), bit_move.promo_piece()));
+                }
+            }
+            apply_game_move(&mut board, mov); //Assumes all the previous moves were valid
+        }
+        promotions
+    }
+    /// Legal moves (UCI notation) after which the side to move's piece on `square` is no
+    /// longer hanging, i.e. attacked and undefended: moving it to a safe square, adding a
+    /// defender, capturing or blocking the attacker. Fails when the square is invalid or does
+    /// not hold a piece of the side to move.
+    pub fn defensive_moves(&self, square: &str) -> Result<Vec<String>, Error> {
+        let square = parse_square(square)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid square"))?;
+        let board = self.compute_current_board();
+        let player = board.turn();
+        if board.get_occupied_player(player).0 & (1 << square.0) == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "No piece of the side to move on that square",
+            ));
+        }
+        Ok(board
+            .generate_moves()
+            .iter()
+            .filter(|mov| {
+                let destination = if mov.get_src() == square {
+                    mov.get_dest()
+                } else {
+                    square
+                };
+                let mut next = board.clone();
+                next.apply_move(**mov);
+                let bit = 1 << destination.0;
+                attack_map(&next, player.other_player()) & bit == 0
+                    || attack_map(&next, player) & bit != 0
+            })
+            .map(|mov| mov.stringify())
+            .collect())
+    }
+    /// Whether the legal move `uci` leaves the opponent with a legal reply or checkmated,
+    /// i.e. does not stalemate them. Fails when the move is not legal.
+    pub fn avoids_stalemate(&self, uci: &str) -> Result<bool, Error> {
+        let mut board = self.compute_current_board();
+        let mov = find_legal_move(&board, uci)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Not a legal move"))?;
+        board.apply_move(mov);
+        Ok(board.in_check() || !board.generate_moves().is_empty())
+    }
+    /// Legal moves in UCI notation that promote to a knight, bishop or rook
+    pub fn underpromotion_moves(&self) -> Vec<String> {
+        let board = self.compute_current_board();
+        board
+            .generate_moves()
+            .iter()
+            .filter(|mov| mov.is_promo() && mov.promo_piece() != pleco::PieceType::Q)
+            .map(|mov| mov.stringify())
+            .collect()
+    }
+    /// Name of the longest line in the embedded opening table that the game follows, e.g.
+    /// "Sicilian Defense" after 1. e4 c5. Only games from the standard start position match.
+    pub fn opening_name(&self) -> Option<String> {
+        if self.initial_board.fen() != Board::start_pos().fen() {
+            return None;
+        }
+        opening_lines()
+            .into_iter()
+            .filter(|line| line.uci_moves.len() <= self.moves.len() && self.agrees_with_line(line))
+            .max_by_key(|line| line.uci_moves.len())
+            .map(|line| line.name)
+    }
+    /// Whether `uci` continues one of the lines in the embedded opening table from the current
+    /// position. Only games from the standard start position can be in book.
+    pub fn is_book_move(&self, uci: &str) -> bool {
+        if self.initial_board.fen() != Board::start_pos().fen() {
+            return false;
+        }
+        opening_lines().iter().any(|line| {
+            line.uci_moves
+                .get(self.moves.len())
+                .is_some_and(|book_move| book_move == uci)
+                && self.agrees_with_line(line)
+        })
+    }
+    /// Whether the game and the opening line agree on every move they both have
+    fn agrees_with_line(&self, line: &OpeningLine) -> bool {
+        line.uci_moves
+            .iter()
+            .zip(self.moves.iter())
+            .all(|(book_move, mov)| *book_move == mov.uci_move)
+    }
+    /// Whether any position (piece placement, side to move, castling rights and en passant
+    /// square, compared by Zobrist key) has occurred three or more times in the game
+    pub fn is_threefold_repetition(&self) -> bool {
+        let mut occurrences: HashMap<u64, u32> = HashMap::new();
+        self.position_hashes().into_iter().any(|hash| {
+            let count = occurrences.entry(hash).or_insert(0);
+            *count += 1;
+            *count >= 3
+        })
+    }
+    /// Whether 100 plies have passed without a pawn move or capture, counting on from the
+    /// halfmove clock of the initial FEN
+    pub fn is_fifty_move_draw(&self) -> bool {
+        self.halfmove_clocks()
+            .last()
+            .is_some_and(|clock| *clock >= 100)
+    }
+    /// Whether neither side has the material to checkmate in the current position: K vs K,
+    /// K+B vs K, K+N vs K, or K+B vs K+B with both bishops on the same square colour.
+    /// K+N+N vs K is not included: mate cannot be forced but is possible, so under FIDE
+    /// rules the game goes on.
+    pub fn is_insufficient_material(&self) -> bool {
+        is_insufficient_material(&self.compute_current_board())
+    }
+    /// Reason a draw can currently be claimed, if any.
+    ///
+    /// Without a claim window (the default) this reports a threefold repetition while the
+    /// repeated position is on the board and the fifty-move rule while the halfmove clock
+    /// is at 100 or more. With `with_draw_claim_window(n)` a claim is only possible during the
+    /// `n` plies after the move that created the condition (0 means only on that move): a
+    /// position occurring for the third or later time, or the halfmove clock reaching 100.
+    /// Once the window has passed the right lapses until the condition is created again.
+    pub fn can_claim_draw(&self) -> Option<DrawReason> {
+        let keys = self.position_keys();
+        let clocks = self.halfmove_clocks();
+        let current_ply = keys.len() - 1;
+        let is_repetition =
+            |ply: usize| keys[..=ply].iter().filter(|key| **key == keys[ply]).count() >= 3;
+        match self.draw_claim_window {
+            None => {
+                if is_repetition(current_ply) {
+                    Some(DrawReason::Threefold)
+                } else if clocks[current_ply] >= 100 {
+                    Some(DrawReason::FiftyMove)
+                } else {
+                    None
+                }
+            }
+            Some(window) => (current_ply.saturating_sub(window as usize)..=current_ply)
+                .rev()
+                .find_map(|ply| {
+                    if is_repetition(ply) {
+                        Some(DrawReason::Threefold)
+                    } else if clocks[ply] >= 100 && (ply == 0 || clocks[ply - 1] < 100) {
+                        Some(DrawReason::FiftyMove)
+                    } else {
+                        None
+                    }
+                }),
+        }
+    }
+    /// Legal moves in UCI notation that would bring about a position for the third time,
+    /// letting the opponent claim a threefold repetition draw right after them
+    pub fn repetition_draw_in_one(&self) -> Vec<String> {
+        let keys = self.position_keys();
+        let board = self.compute_current_board();
+        let castling = self.current_chess960_castling();
+        board
+            .generate_moves()
+            .iter()
+            .filter(|mov| {
+                let mut next = board.clone();
+                next.apply_move(**mov);
+                let key = match &castling {
+                    Some(castling) => with_castling_field(
+                        &normalized_fen(&next),
+                        &chess960_castling_after(&board, castling, &mov.stringify()),
+                    ),
+                    None => normalized_fen(&next),
+                };
+                keys.iter().filter(|previous| **previous == key).count() >= 2
+            })
+            .map(|mov| mov.stringify())
+            .collect()
+    }
+    /// Study-style JSON for analysis front-ends: an array of nodes
+    /// `{ "ply", "san", "uci", "fen", "clock" }`, starting with a root node for the initial
+    /// position (ply 0, `san` and `uci` null) followed by one node per move with the position
+    /// after it. `clock` is the mover's remaining time in milliseconds, or null when untimed.
+    pub fn to_study_json(&self) -> serde_json::Value {
+        let mut board = self.initial_board.clone();
+        let castling = self.chess960_castling_per_ply();
+        let fen_at = |board: &Board, ply: usize| {
+            fen_with_castling(board, castling.as_ref().map(|castling| &castling[ply]))
+        };
+        let mut nodes = vec![serde_json::json!({
+            "ply": 0,
+            "san": null,
+            "uci": null,
+            "fen": fen_at(&board, 0),
+            "clock": null,
+        })];
+        let clocks = self.remaining_time_annotations();
+        for (ply, (mov, (_, remaining))) in self.moves.iter().zip(clocks).enumerate() {
+            let san = mov.san_on(&board);
+            apply_game_move(&mut board, mov); //Assumes all the previous moves were valid
+            let clock = if self.is_timed() {
+                Some(remaining)
+            } else {
+                None
+            };
+            nodes.push(serde_json::json!({
+                "ply": ply + 1,
+                "san": san,
+                "uci": mov.uci_move,
+                "fen": fen_at(&board, ply + 1),
+                "clock": clock,
+            }));
+        }
+        serde_json::Value::Array(nodes)
+    }
+    /// URL-safe base64 (no padding) of the game's compact binary encoding, see `to_bytes`
+    pub fn to_share_token(&self) -> Result<String, ChessError> {
+        Ok(base64_encode(&self.to_bytes()?))
+    }
+    /// Decodes a token made by `to_share_token`, failing on malformed tokens or illegal moves
+    pub fn from_share_token(token: &str) -> Result<ChessGame, ChessError> {
+        let bytes = base64_decode(token)
+            .ok_or_else(|| ChessError::InvalidBinaryData(String::from("invalid share token")))?;
+        ChessGame::from_bytes(&bytes)
+    }
+    /// Compact binary encoding, a smaller alternative to the serde format for storing many
+    /// games. Move comments and NAGs are not kept. Little endian: the initial FEN (u8
+    /// length, empty for the start position), start time (u64), time limit and increment or
+    /// delay (u32 each), a flags byte for the boolean options, the time control mode (top two
+    /// bits) and which of the draw claim window (u16), black's time limit (u32) and the
+    /// metadata (six strings with a u8 length, up to 255 bytes each) follow, then a second
+    /// flags byte whose lowest bit marks a result. With mode 3 the stages come next: a count
+    /// (u8) and per stage its moves (u16, 0 for the rest of the game), base time and
+    /// increment (u32 each). Then the move count (u16) and per move its squares and
+    /// promotion packed in a u16 plus `time_taken` (u32), and last, only for a resigned or
+    /// agreed game, its result (u8, see `result_code`). Fails with
+    /// `ChessError::TooLargeForBinary` when a metadata value, the stages or the moves do not
+    /// fit their length field.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ChessError> {
+        let too_long = |what: &str| ChessError::TooLargeForBinary(String::from(what));
+        let mut bytes = Vec::new();
+        let fen = self.initial_fen();
+        let fen = if fen == Board::start_pos().fen() {
+            String::new()
+        } else {
+            fen
+        };
+        bytes.push(u8::try_from(fen.len()).map_err(|_| too_long("fen"))?);
+        bytes.extend_from_slice(fen.as_bytes());
+        bytes.extend_from_slice(&self.start_time.to_le_bytes());
+        bytes.extend_from_slice(&self.time_limit.to_le_bytes());
+        bytes.extend_from_slice(&self.time_control.per_move_credit().to_le_bytes());
+        let mode = match self.time_control {
+            _ if !self.time_control_stages.is_empty() => 3,
+            TimeControl::SuddenDeath | TimeControl::Fischer(_) => 0,
+            TimeControl::Bronstein(_) => 1,
+            TimeControl::SimpleDelay(_) => 2,
+        };
+        let flags = self.timeout_insufficient_material_is_draw as u8
+            | (self.auto_draw_on_insufficient_material as u8) << 1
+            | (self.auto_first_move_time as u8) << 2
+            | (self.draw_claim_window.is_some() as u8) << 3
+            | (self.black_time_limit.is_some() as u8) << 4
+            | (self.metadata().iter().any(|value| value.is_some()) as u8) << 5
+            | mode << 6;
+        bytes.push(flags);
+        if let Some(window) = self.draw_claim_window {
+            bytes.extend_from_slice(&window.to_le_bytes());
+        }
+        if let Some(black_time_limit) = self.black_time_limit {
+            bytes.extend_from_slice(&black_time_limit.to_le_bytes());
+        }
+        if flags & 32 != 0 {
+            for value in self.metadata() {
+                let value = value.map(String::as_bytes).unwrap_or_default();
+                bytes.push(u8::try_from(value.len()).map_err(|_| too_long("metadata value"))?);
+                bytes.extend_from_slice(value);
+            }
+        }
+        bytes.push(self.result_override.is_some() as u8);
+        if mode == 3 {
+            let stage_count = self.time_control_stages.len();
+            bytes.push(u8::try_from(stage_count).map_err(|_| too_long("time control stages"))?);
+            for stage in self.time_control_stages.iter() {
+                let moves = u16::try_from(stage.moves.unwrap_or(0))
+                    .map_err(|_| too_long("time control stage"))?;
+                bytes.extend_from_slice(&moves.to_le_bytes());
+                bytes.extend_from_slice(&stage.base_time.to_le_bytes());
+                bytes.extend_from_slice(&stage.increment.to_le_bytes());
+            }
+        }
+        let move_count = u16::try_from(self.moves.len()).map_err(|_| too_long("move list"))?;
+        bytes.extend_from_slice(&move_count.to_le_bytes());
+        for mov in self.moves.iter() {
+            bytes.extend_from_slice(&pack_uci_move(&mov.uci_move).to_le_bytes());
+            bytes.extend_from_slice(&mov.time_taken.to_le_bytes());
+        }
+        if let Some(result) = self.result_override {
+            bytes.push(result_code(result));
+        }
+        Ok(bytes)
+    }
+    /// Decodes `to_bytes` output. Truncated, malformed or trailing data fails with
+    /// `ChessError::InvalidBinaryData`, an unreadable initial position with the FEN errors and
+    /// an illegal move with `ChessError::IllegalMove`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<ChessGame, ChessError> {
+        let invalid = |reason: &str| ChessError::InvalidBinaryData(String::from(reason));
+        let mut reader = ByteReader { bytes, position: 0 };
+        let fen_length = reader.read_u8()? as usize;
+        let fen = String::from_utf8(reader.take(fen_length)?.to_vec())
+            .map_err(|_| invalid("fen is not UTF-8"))?;
+        let (initial_board, chess960_castling) = if fen.is_empty() {
+            (Board::start_pos(), None)
+        } else {
+            let (board, castling) = board_from_fen(&fen)?;
+            check_side_not_to_move(&board)?;
+            (board, castling)
+        };
+        let start_time = reader.read_u64()?;
+        let time_limit = reader.read_u32()?;
+        let per_move_credit = reader.read_u32()?;
+        let flags = reader.read_u8()?;
+        let time_control = match flags >> 6 {
+            1 => TimeControl::Bronstein(per_move_credit),
+            2 => TimeControl::SimpleDelay(per_move_credit),
+            _ => TimeControl::from_increment(per_move_credit),
+        };
+        let draw_claim_window = if flags & 8 != 0 {
+            Some(reader.read_u16()?)
+        } else {
+            None
+        };
+        let black_time_limit = if flags & 16 != 0 {
+            Some(reader.read_u32()?)
+        } else {
+            None
+        };
+        let mut metadata: [Option<String>; 6] = Default::default();
+        if flags & 32 != 0 {
+            for value in metadata.iter_mut() {
+                let length = reader.read_u8()? as usize;
+                let text = String::from_utf8(reader.take(length)?.to_vec())
+                    .map_err(|_| invalid("metadata is not UTF-8"))?;
+                *value = Some(text).filter(|text| !text.is_empty());
+            }
+        }
+        let [white_name, black_name, event, site, date, round] = metadata;
+        let has_result = reader.read_u8()? & 1 != 0;
+        let mut time_control_stages = Vec::new();
+        if flags >> 6 == 3 {
+            for _ in 0..reader.read_u8()? {
+                let moves = reader.read_u16()?;
+                time_control_stages.push(TimeControlStage {
+                    moves: Some(moves as u32).filter(|moves| *moves > 0),
+                    base_time: reader.read_u32()?,
+                    increment: reader.read_u32()?,
+                });
+            }
+        }
+        let move_count = reader.read_u16()?;
+        let mut board = initial_board.clone();
+        let mut castling = chess960_castling.clone();
+        let mut moves = Vec::with_capacity(move_count as usize);
+        for _ in 0..move_count {
+            let uci_move = unpack_uci_move(reader.read_u16()?);
+            let time_taken = reader.read_u32()?;
+            let mov = play_legal_move(&mut board, &mut castling, &uci_move, time_taken)
+                .ok_or(ChessError::IllegalMove { uci: uci_move })?;
+            moves.push(mov);
+        }
+        let result_override = if has_result {
+            let code = reader.read_u8()?;
+            Some(
+                result_from_code(code)
+                    .filter(|result| is_result_override(*result))
+                    .ok_or_else(|| {
+                        ChessError::InvalidBinaryData(format!("result code {}", code))
+                    })?,
+            )
+        } else {
+            None
+        };
+        if !reader.is_at_end() {
+            return Err(invalid("trailing bytes after the game data"));
+        }
+        Ok(ChessGame {
+            initial_board,
+            moves,
+            start_time,
+            time_limit,
+            time_control,
+            timeout_insufficient_material_is_draw: flags & 1 != 0,
+            draw_claim_window,
+            auto_draw_on_insufficient_material: flags & 2 != 0,
+            auto_first_move_time: flags & 4 != 0,
+            black_time_limit,
+            white_name,
+            black_name,
+            event,
+            site,
+            date,
+            round,
+            time_control_stages,
+            result_override,
+            chess960_castling,
+        })
+    }
+    /// White and black names, event, site, date and round, in that order
+    fn metadata(&self) -> [Option<&String>; 6] {
+        [
+            self.white_name.as_ref(),
+            self.black_name.as_ref(),
+            self.event.as_ref(),
+            self.site.as_ref(),
+            self.date.as_ref(),
+            self.round.as_ref(),
+        ]
+    }
+    /// Checks that the move list is legal from the initial board with no move after a
+    /// checkmate, that a stored result is a resignation or an agreed draw made while the game
+    /// was still going, and that no increment or delay of a timed game, in any stage, exceeds
+    /// either base time. Returns a description of the first violation, for servers loading
+    /// persisted games.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        let mut board = self.initial_board.clone();
+        let mut castling = self.chess960_castling.clone();
+        for (index, mov) in self.moves.iter().enumerate() {
+            if board.checkmate() {
+                return Err(format!(
+                    "move {} ({}) is played after checkmate",
+                    index + 1,
+                    mov.uci_move
+                ));
+            }
+            if play_legal_move(&mut board, &mut castling, &mov.uci_move, mov.time_taken).is_none() {
+                return Err(format!("move {} ({}) is illegal", index + 1, mov.uci_move));
+            }
+        }
+        if let Some(result) = self.result_override {
+            if !is_result_override(result) {
+                return Err(format!(
+                    "result {:?} is not a resignation or an agreed draw",
+                    result
+                ));
+            }
+            let played_out = ChessGame {
+                result_override: None,
+                ..self.clone()
+            };
+            if played_out.has_ended() && played_out.settled_result() != result {
+                return Err(format!(
+                    "result {:?} contradicts {:?} on the board",
+                    result,
+                    played_out.settled_result()
+                ));
+            }
+        }
+        if self.is_timed() {
+            let credits = std::iter::once(self.time_control.per_move_credit())
+                .chain(self.time_control_stages.iter().map(|stage| stage.increment));
+            for credit in credits {
+                for player in [pleco::Player::White, pleco::Player::Black] {
+                    if credit > self.time_limit_for(player) {
+                        return Err(format!(
+                            "increment {} exceeds the time limit {} of {:?}",
+                            credit,
+                            self.time_limit_for(player),
+                            player
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    /// How the game is heading for a draw, for commentary. When the current position has
+    /// occurred before, the moves since its previous occurrence are a repetition cycle: a
+    /// `PerpetualCheck` if every move of one side in it gave check, a `PositionalRepetition`
+    /// otherwise. Without a repetition, a known fortress (see `is_known_fortress`) is a
+    /// `Fortress`. `None` when neither applies.
+    pub fn drawing_mechanism(&self) -> Option<DrawMechanism> {
+        let keys = self.position_keys();
+        let current_ply = keys.len() - 1;
+        let previous_ply = match keys[..current_ply]
+            .iter()
+            .rposition(|key| *key == keys[current_ply])
+        {
+            Some(ply) => ply,
+            None if self.is_known_fortress() => return Some(DrawMechanism::Fortress),
+            None => return None,
+        };
+        let checks = self.checks_per_ply();
+        // plies previous_ply + 1 ..= current_ply, alternating between the two sides
+        let is_perpetual = |first: usize| {
+            (previous_ply + first..=current_ply)
+                .step_by(2)
+                .all(|ply| checks[ply])
+        };
+        if is_perpetual(1) || is_perpetual(2) {
+            Some(DrawMechanism::PerpetualCheck)
+        } else {
+            Some(DrawMechanism::PositionalRepetition)
+        }
+    }
+    /// Whether the side to move is in check at each ply, starting with the initial position
+    fn checks_per_ply(&self) -> Vec<bool> {
+        let mut board = self.initial_board.clone();
+        let mut checks = vec![board.in_check()];
+        for mov in self.moves.iter() {
+            apply_game_move(&mut board, mov); //Assumes all the previous moves were valid
+            checks.push(board.in_check());
+        }
+        checks
+    }
+    /// Halfmove clock (plies since the last capture or pawn move) after each half-move,
+    /// starting from the counter in the initial FEN
+    fn halfmove_clocks(&self) -> Vec<u16> {
+        let mut board = self.initial_board.clone();
+        let mut clock = fen_field(&board, 4).unwrap_or(0);
+        let mut clocks = vec![clock];
+        for mov in self.moves.iter() {
+            if let Some(mov) = mov.bit_move_on(&board) {
+                let is_pawn_move =
+                    board.piece_at_sq(mov.get_src()).type_of() == pleco::PieceType::P;
+                clock = if is_pawn_move || mov.is_capture() {
+                    0
+                } else {
+                    clock + 1
+                };
+                board.apply_move(mov);
+            } else if apply_game_move(&mut board, mov) {
+                // a Chess960 castle
+                clock += 1;
+            }
+            clocks.push(clock);
+        }
+        clocks
+    }
+    /// Rough memory footprint of the game: the struct itself, the move list allocation (each
+    /// move inline with its resolved `BitMove`) and the heap bytes of every UCI string. Heap
+    /// data owned by pleco's `Board` is not counted.
+    pub fn estimated_size_bytes(&self) -> usize {
+        let move_strings: usize = self.moves.iter().map(|mov| mov.uci_move.capacity()).sum();
+        std::mem::size_of::<ChessGame>()
+            + self.moves.capacity() * std::mem::size_of::<Move>()
+            + move_strings
+    }
+    /// Zobrist hash of the current position
+    pub fn position_hash(&self) -> u64 {
+        self.compute_current_board().zobrist()
+    }
+    /// Catches up a client that last saw the position with `known_position_hash`: if that
+    /// position occurs in the game, only the moves played after its latest occurrence are
+    /// returned, otherwise the whole game.
+    pub fn sync_against(&self, known_position_hash: u64) -> SyncResult {
+        match self
+            .position_hashes()
+            .iter()
+            .rposition(|hash| *hash == known_position_hash)
+        {
+            Some(ply) => SyncResult::Delta(self.moves[ply..].to_vec()),
+            None => SyncResult::Snapshot(self.clone()),
+        }
+    }
+    /// Zobrist hash of the position after each half-move, starting with the initial position
+    fn position_hashes(&self) -> Vec<u64> {
+        let mut board = self.initial_board.clone();
+        let mut hashes = vec![board.zobrist()];
+        for mov in self.moves.iter() {
+            apply_game_move(&mut board, mov); //Assumes all the previous moves were valid
+            hashes.push(board.zobrist());
+        }
+        hashes
+    }
+    /// Normalized FEN of the position after each half-move, starting with the initial position,
+    /// with the castling rights of a Chess960 game
+    fn position_keys(&self) -> Vec<String> {
+        let mut board = self.initial_board.clone();
+        let mut keys = vec![normalized_fen(&board)];
+        for mov in self.moves.iter() {
+            apply_game_move(&mut board, mov); //Assumes all the previous moves were valid
+            keys.push(normalized_fen(&board));
+        }
+        if let Some(castling) = self.chess960_castling_per_ply() {
+            for (key, castling) in keys.iter_mut().zip(castling) {
+                *key = with_castling_field(key, &castling);
+            }
+        }
+        keys
+    }
+}
+
+/// White's back rank (uppercase letters, a to h) of Chess960 starting position
+/// `position_id` in the standard numbering, 518 being the classical array. `None` above 959.
+fn chess960_back_rank(position_id: u16) -> Option<[char; 8]> {
+    if position_id > 959 {
+        return None;
+    }
+    let mut rank = [' '; 8];
+    let mut id = position_id as usize;
+    rank[2 * (id % 4) + 1] = 'B';
+    id /= 4;
+    rank[2 * (id % 4)] = 'B';
+    id /= 4;
+    let empty_files =
+        |rank: &[char; 8]| -> Vec<usize> { (0..8).filter(|file| rank[*file] == ' ').collect() };
+    rank[empty_files(&rank)[id % 6]] = 'Q';
+    id /= 6;
+    let (first, second) = [
+        (0, 1),
+        (0, 2),
+        (0, 3),
+        (0, 4),
+        (1, 2),
+        (1, 3),
+        (1, 4),
+        (2, 3),
+        (2, 4),
+        (3, 4),
+    ][id];
+    let empty = empty_files(&rank);
+    rank[empty[first]] = 'N';
+    rank[empty[second]] = 'N';
+    for (file, piece) in empty_files(&rank).into_iter().zip(['R', 'K', 'R']) {
+        rank[file] = piece;
+    }
+    Some(rank)
+}
+
+/// Shredder-FEN of Chess960 starting position `position_id` (0 to 959), whose castling
+/// field names the rooks' files, e.g. "HAha" for the classical position 518
+pub fn chess960_fen(position_id: u16) -> Option<String> {
+    let rank = chess960_back_rank(position_id)?;
+    let white: String = rank.iter().collect();
+    let rook_files: String = (0..8)
+        .rev()
+        .filter(|file| rank[*file] == 'R')
+        .map(|file| (b'A' + file as u8) as char)
+        .collect();
+    Some(format!(
+        "{}/pppppppp/8/8/8/8/PPPPPPPP/{} w {}{} - 0 1",
+        white.to_lowercase(),
+        white,
+        rook_files,
+        rook_files.to_lowercase()
+    ))
+}
+
+/// First ply at which the move lists of two games differ, or `None` if one is a prefix of the
+/// other. Games that start from different positions diverge at ply 0.
+pub fn divergence_ply(a: &ChessGame, b: &ChessGame) -> Option<usize> {
+    if a.initial_board.fen() != b.initial_board.fen() {
+        return Some(0);
+    }
+    a.moves
+        .iter()
+        .zip(b.moves.iter())
+        .position(|(mov_a, mov_b)| mov_a.uci_move != mov_b.uci_move)
+}
+
+fn current_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
+
+const DRAW_REASONS: [DrawReason; 6] = [
+    DrawReason::Stalemate,
+    DrawReason::Threefold,
+    DrawReason::FiftyMove,
+    DrawReason::InsufficientMaterial,
+    DrawReason::Agreement,
+    DrawReason::TimeoutVsInsufficientMaterial,
+];
+
+/// Byte for a result in the binary encoding: 0 ongoing, 1 white wins, 2 black wins, then
+/// the draws from 3 in the order of `DRAW_REASONS`
+fn result_code(result: GameResult) -> u8 {
+    match result {
+        GameResult::Ongoing => 0,
+        GameResult::WhiteWins => 1,
+        GameResult::BlackWins => 2,
+        GameResult::Draw(reason) => {
+            3 + DRAW_REASONS
+                .iter()
+                .position(|draw_reason| *draw_reason == reason)
+                .unwrap_or(0) as u8
+        }
+    }
+}
+
+/// Whether `result` is one `resign` or `agree_draw` can record, the only results a game
+/// keeps as `result_override`
+fn is_result_override(result: GameResult) -> bool {
+    matches!(
+        result,
+        GameResult::WhiteWins | GameResult::BlackWins | GameResult::Draw(DrawReason::Agreement)
+    )
+}
+
+/// Rejects a deserialized `result_override` that `is_result_override` does not allow
+fn check_result_override<E: de::Error>(
+    result_override: Option<GameResult>,
+) -> Result<Option<GameResult>, E> {
+    match result_override {
+        Some(result) if !is_result_override(result) => Err(E::custom(format!(
+            "result_override must be a resignation or an agreed draw, not {:?}",
+            result
+        ))),
+        _ => Ok(result_override),
+    }
+}
+
+fn result_from_code(code: u8) -> Option<GameResult> {
+    match code {
+        0 => Some(GameResult::Ongoing),
+        1 => Some(GameResult::WhiteWins),
+        2 => Some(GameResult::BlackWins),
+        _ => DRAW_REASONS
+            .get(code as usize - 3)
+            .map(|reason| GameResult::Draw(*reason)),
+    }
+}
+
+/// `start_time` used to be saved as the low 32 bits of the epoch time. Such values are
+/// placed in the latest 2^32 ms window (about 49.7 days) ending at `now_millis`.
+fn restore_truncated_start_time(start_time: u64, now_millis: u64) -> u64 {
+    if start_time > u32::MAX as u64 {
+        return start_time;
+    }
+    let window = 1u64 << 32;
+    let restored = (now_millis & !(window - 1)) | start_time;
+    if restored > now_millis {
+        restored.saturating_sub(window)
+    } else {
+        restored
+    }
+}
+
+fn format_clock(millis: i64) -> String {
+    let seconds = millis.max(0) / 1000;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+/// Plays a move of the game on `board`, through its resolved `BitMove` when it has one and
+/// by its UCI string otherwise. Returns whether the move was played.
+fn apply_game_move(board: &mut Board, mov: &Move) -> bool {
+    match mov.bit_move {
+        Some(bit_move) => {
+            board.apply_move(bit_move);
+            true
+        }
+        None => board.apply_uci_move(&mov.uci_move) || apply_chess960_castle(board, &mov.uci_move),
+    }
+}
+
+/// `apply_game_move` that also keeps the Chess960 castling rights in `castling` up to date
+fn apply_game_move_with_castling(board: &mut Board, castling: &mut Option<String>, mov: &Move) {
+    if let Some(rights) = castling.as_mut() {
+        *rights = chess960_castling_after(board, rights, &mov.uci_move);
+    }
+    apply_game_move(board, mov); //Assumes all the previous moves were valid
+}
+
+/// Plays `uci` on `board` when it is legal there, Chess960 castles included when `castling`
+/// holds the rights for them, keeping `castling` up to date. Returns the move with its
+/// `BitMove` resolved (none for a Chess960 castle), or `None` for an illegal move.
+fn play_legal_move(
+    board: &mut Board,
+    castling: &mut Option<String>,
+    uci: &str,
+    time_taken: u32,
+) -> Option<Move> {
+    let mov = match find_legal_move(board, uci) {
+        Some(bit_move) => Move::resolved(bit_move, time_taken),
+        None if castling
+            .as_deref()
+            .is_some_and(|rights| is_legal_chess960_castle(board, rights, uci)) =>
+        {
+            Move::new(String::from(uci), time_taken)
+        }
+        None => return None,
+    };
+    apply_game_move_with_castling(board, castling, &mov);
+    Some(mov)
+}
+
+/// Resolves the `BitMove` of each move by replaying them from `initial_board`. Moves from the
+/// first illegal one on stay unresolved and are replayed by their UCI string.
+fn resolve_moves(initial_board: &Board, moves: &mut [Move]) {
+    let mut board = initial_board.clone();
+    for mov in moves.iter_mut() {
+        match find_legal_move(&board, &mov.uci_move) {
+            Some(bit_move) => {
+                mov.bit_move = Some(bit_move);
+                board.apply_move(bit_move);
+            }
+            None if apply_chess960_castle(&mut board, &mov.uci_move) => {}
+            None => break,
+        }
+    }
+}
+
+fn find_legal_move(board: &Board, uci: &str) -> Option<BitMove> {
+    board
+        .generate_moves()
+        .iter()
+        .find(|mov| mov.stringify() == uci)
+        .cloned()
+}
+
+/// Reads `fen` into a board, keeping aside the Chess960 castling rights pleco cannot hold. A
+/// Shredder-FEN castling field naming the rooks' files (e.g. "HFhf") is returned and the
+/// board gets "-", unless it only names corner rooks of a king on the e-file, which pleco
+/// castles itself as "KQkq". Each named rook must be on its side's back rank with the king,
+/// at most one on either side of it.
+fn board_from_fen(fen: &str) -> Result<(Board, Option<String>), ChessError> {
+    let read = |fen: &str| {
+        Board::from_fen(fen).map_err(|err| ChessError::InvalidFen(format!("{:?}", err)))
+    };
+    let castling = fen.split_whitespace().nth(2).unwrap_or("-");
+    if castling == "-" || !castling.chars().all(|c| matches!(c, 'A'..='H' | 'a'..='h')) {
+        return Ok((read(fen)?, None));
+    }
+    let board = read(&with_castling_field(fen, ""))?;
+    let mut wings = Vec::new();
+    let mut classical = String::new();
+    for letter in castling.chars() {
+        let player = castling_letter_owner(letter);
+        let rook = castling_rook_square(letter);
+        let king = board.king_sq(player);
+        let piece = board.piece_at_sq(rook);
+        let kingside = rook.0 > king.0;
+        if piece.player() != Some(player)
+            || piece.type_of() != pleco::PieceType::R
+            || king.0 / 8 != rook.0 / 8
+            || wings.contains(&(player, kingside))
+        {
+            return Err(ChessError::InvalidFen(format!(
+                "No castling rook for {} in {}",
+                letter, fen
+            )));
+        }
+        wings.push((player, kingside));
+        if king.0 % 8 == 4 && rook.0 % 8 == if kingside { 7 } else { 0 } {
+            let right = if kingside { 'K' } else { 'Q' };
+            classical.push(match player {
+                pleco::Player::White => right,
+                pleco::Player::Black => right.to_ascii_lowercase(),
+            });
+        }
+    }
+    if classical.len() == castling.len() {
+        let classical: String = "KQkq".chars().filter(|c| classical.contains(*c)).collect();
+        return Ok((read(&with_castling_field(fen, &classical))?, None));
+    }
+    Ok((board, Some(String::from(castling))))
+}
+
+/// `fen` with its castling field replaced by `castling`, "-" when that is empty
+fn with_castling_field(fen: &str, castling: &str) -> String {
+    let castling = if castling.is_empty() { "-" } else { castling };
+    fen.split_whitespace()
+        .enumerate()
+        .map(|(index, field)| if index == 2 { castling } else { field })
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// FEN of `board` with the Chess960 castling rights `castling` when pleco cannot hold them
+fn fen_with_castling(board: &Board, castling: Option<&String>) -> String {
+    match castling {
+        Some(castling) => with_castling_field(&board.fen(), castling),
+        None => board.fen(),
+    }
+}
+
+/// Side owning a Shredder-FEN castling letter, white for uppercase
+fn castling_letter_owner(letter: char) -> pleco::Player {
+    if letter.is_ascii_uppercase() {
+        pleco::Player::White
+    } else {
+        pleco::Player::Black
+    }
+}
+
+/// Square of the rook named by a Shredder-FEN castling letter: its file on the owner's back rank
+fn castling_rook_square(letter: char) -> pleco::SQ {
+    let file = letter.to_ascii_lowercase() as u8 - b'a';
+    match castling_letter_owner(letter) {
+        pleco::Player::White => pleco::SQ(file),
+        pleco::Player::Black => pleco::SQ(56 + file),
+    }
+}
+
+/// Squares of `uci` read as the side to move's king taking its own rook, how Chess960 castles
+/// are written (e.g. "g1h1"): the king's, the rook's, then where the king and rook land, the g
+/// and f files on the kingside or the c and d files on the queenside
+fn chess960_castle_squares(board: &Board, uci: &str) -> Option<[pleco::SQ; 4]> {
+    let player = board.turn();
+    let king = parse_square(uci.get(0..2)?)?;
+    let rook = parse_square(uci.get(2..)?)?;
+    let piece = board.piece_at_sq(rook);
+    if king.0 != board.king_sq(player).0
+        || piece.player() != Some(player)
+        || piece.type_of() != pleco::PieceType::R
+        || king.0 / 8 != rook.0 / 8
+    {
+        return None;
+    }
+    let back_rank = king.0 - king.0 % 8;
+    let (king_file, rook_file) = if rook.0 > king.0 { (6, 5) } else { (2, 3) };
+    Some([
+        king,
+        rook,
+        pleco::SQ(back_rank + king_file),
+        pleco::SQ(back_rank + rook_file),
+    ])
+}
+
+/// Why the Chess960 castle with `squares` (see `chess960_castle_squares`) is illegal on
+/// `board` with the castling rights `castling` left, checked in the order `CastleError`
+/// declares them; `None` when it is legal. The squares the king and rook cross or land on
+/// must be empty but for those two.
+fn chess960_castle_error(
+    board: &Board,
+    castling: &str,
+    [king, rook, king_to, rook_to]: [pleco::SQ; 4],
+) -> Option<CastleError> {
+    let player = board.turn();
+    let right = (b'a' + rook.0 % 8) as char;
+    let right = match player {
+        pleco::Player::White => right.to_ascii_uppercase(),
+        pleco::Player::Black => right,
+    };
+    let span = |from: pleco::SQ, to: pleco::SQ| -> u64 {
+        (from.0.min(to.0)..=from.0.max(to.0)).fold(0, |bits, square| bits | 1 << square)
+    };
+    let others = board.get_occupied().0 & !(1 << king.0) & !(1 << rook.0);
+    let attacked = attack_map(board, player.other_player());
+    let mut after = board.clone();
+    let castle = format!("{}{}", square_name(king), square_name(rook));
+    if !castling.contains(right) {
+        Some(CastleError::NoRights)
+    } else if (span(king, king_to) | span(rook, rook_to)) & others != 0 {
+        Some(CastleError::PathBlocked)
+    } else if board.in_check() {
+        Some(CastleError::KingInCheck)
+    } else if attacked & span(king, king_to) & !(1 << king_to.0) != 0 {
+        Some(CastleError::PassesThroughCheck)
+    } else if !apply_chess960_castle(&mut after, &castle) || check_side_not_to_move(&after).is_err()
+    {
+        Some(CastleError::LandsInCheck)
+    } else {
+        None
+    }
+}
+
+/// Whether `uci` is a legal Chess960 castle on `board` with the castling rights `castling` left
+fn is_legal_chess960_castle(board: &Board, castling: &str, uci: &str) -> bool {
+    chess960_castle_squares(board, uci)
+        .is_some_and(|squares| chess960_castle_error(board, castling, squares).is_none())
+}
+
+/// Plays the Chess960 castle `uci` (see `chess960_castle_squares`) on `board`, rebuilding it
+/// from FEN since pleco only castles between the classical squares. Returns whether `uci` is
+/// such a castle; whether it is legal is not checked.
+fn apply_chess960_castle(board: &mut Board, uci: &str) -> bool {
+    let Some([king, rook, king_to, rook_to]) = chess960_castle_squares(board, uci) else {
+        return false;
+    };
+    let fen = board.fen();
+    let fields: Vec<&str> = fen.split_whitespace().collect();
+    // a1 first, as pleco numbers the squares
+    let mut squares: Vec<char> = fields[0]
+        .split('/')
+        .rev()
+        .flat_map(|rank| {
+            rank.chars().flat_map(|c| match c.to_digit(10) {
+                Some(empty) => vec!['.'; empty as usize],
+                None => vec![c],
+            })
+        })
+        .collect();
+    let (king_piece, rook_piece) = (squares[king.0 as usize], squares[rook.0 as usize]);
+    squares[king.0 as usize] = '.';
+    squares[rook.0 as usize] = '.';
+    squares[king_to.0 as usize] = king_piece;
+    squares[rook_to.0 as usize] = rook_piece;
+    let placement: Vec<String> = squares
+        .chunks(8)
+        .rev()
+        .map(|rank| {
+            let mut text = String::new();
+            let mut empty = 0;
+            for square in rank {
+                if *square == '.' {
+                    empty += 1;
+                    continue;
+                }
+                if empty > 0 {
+                    text.push_str(&empty.to_string());
+                    empty = 0;
+                }
+                text.push(*square);
+            }
+            if empty > 0 {
+                text.push_str(&empty.to_string());
+            }
+            text
+        })
+        .collect();
+    let (side, moves_played) = match board.turn() {
+        pleco::Player::White => ("b", 0),
+        pleco::Player::Black => ("w", 1),
+    };
+    let next = format!(
+        "{} {} {} - {} {}",
+        placement.join("/"),
+        side,
+        fields[2],
+        fen_field(board, 4).unwrap_or(0) + 1,
+        fen_field(board, 5).unwrap_or(1) + moves_played
+    );
+    match Board::from_fen(&next) {
+        Ok(next) => {
+            *board = next;
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Chess960 castling rights `castling` once `uci` is played on `board`: a side loses its
+/// rights when its king moves, and a rook's right goes when its square is moved from or to
+fn chess960_castling_after(board: &Board, castling: &str, uci: &str) -> String {
+    let (Some(from), Some(to)) = (
+        uci.get(0..2).and_then(parse_square),
+        uci.get(2..4).and_then(parse_square),
+    ) else {
+        return String::from(castling);
+    };
+    let mover = board.turn();
+    let king_moves = from.0 == board.king_sq(mover).0;
+    castling
+        .chars()
+        .filter(|letter| {
+            let rook = castling_rook_square(*letter);
+            !(king_moves && castling_letter_owner(*letter) == mover)
+                && rook.0 != from.0
+                && rook.0 != to.0
+        })
+        .collect()
+}
+
+/// Legal Chess960 castles of the side to move on `board` with the castling rights `castling`
+fn chess960_castles(board: &Board, castling: &str) -> Vec<String> {
+    let king = square_name(board.king_sq(board.turn()));
+    castling
+        .chars()
+        .map(|letter| format!("{}{}", king, square_name(castling_rook_square(letter))))
+        .filter(|uci| is_legal_chess960_castle(board, castling, uci))
+        .collect()
+}
+
+/// SAN of the Chess960 castle `uci` on `board`, "O-O" or "O-O-O" with a `+` or `#` suffix when
+/// it gives check or mate; `None` when `uci` is not such a castle
+fn chess960_castle_san(board: &Board, uci: &str) -> Option<String> {
+    let [king, rook, _, _] = chess960_castle_squares(board, uci)?;
+    let mut san = String::from(if rook.0 > king.0 { "O-O" } else { "O-O-O" });
+    let mut after = board.clone();
+    apply_chess960_castle(&mut after, uci);
+    if after.checkmate() {
+        san.push('#');
+    } else if after.in_check() {
+        san.push('+');
+    }
+    Some(san)
+}
+
+/// UCI of every legal move `san` can stand for on `board` (see `san_candidates`), including
+/// the Chess960 castle it names when `castling` holds the rights for one
+fn san_candidates_uci(board: &Board, castling: Option<&str>, san: &str) -> Vec<String> {
+    let mut candidates: Vec<String> = san_candidates(board, san)
+        .iter()
+        .map(|mov| mov.stringify())
+        .collect();
+    if let Some(castling) = castling {
+        let san = san.trim_end_matches(['+', '#', '!', '?']).replace('0', "O");
+        candidates.extend(chess960_castles(board, castling).into_iter().filter(|uci| {
+            chess960_castle_san(board, uci)
+                .is_some_and(|castle| castle.trim_end_matches(['+', '#']) == san)
+        }));
+    }
+    candidates
+}
+
+/// Numeric FEN field of `board`, e.g. 4 for the halfmove clock and 5 for the fullmove number
+fn fen_field(board: &Board, index: usize) -> Option<u16> {
+    board.fen().split_whitespace().nth(index)?.parse().ok()
+}
+
+/// Shortest legal move sequence (UCI notation) leading from `start_fen` to the position in
+/// `end_fen`, comparing positions without the move counters. Lines are tried by iterative
+/// deepening up to `max_ply` plies, capped at `MAX_INFER_PLY`, with moves at each step
+/// ordered by an engine search of `depth` plies (at most 1) so the most plausible lines are
+/// found first. Returns `None` when either FEN is invalid or no line is found within the cap.
+pub fn infer_line(start_fen: &str, end_fen: &str, max_ply: u16, depth: u16) -> Option<Vec<String>> {
+    let mut board = Board::from_fen(start_fen).ok()?;
+    let target = Board::from_fen(end_fen).ok()?;
+    let target_fen = normalized_fen(&target);
+    let depth = depth.min(MAX_INFER_ORDERING_DEPTH);
+    let mut line = Vec::new();
+    (0..=max_ply.min(MAX_INFER_PLY))
+        .find(|limit| search_line(&mut board, &target, &target_fen, *limit, depth, &mut line))
+        .map(|_| line)
+}
+
+/// Deepest line `infer_line` will search for, the search being exponential in it
+const MAX_INFER_PLY: u16 = 4;
+
+/// Deepest engine search `infer_line` runs to order the moves at each node
+const MAX_INFER_ORDERING_DEPTH: u16 = 1;
+
+fn search_line(
+    board: &mut Board,
+    target: &Board,
+    target_fen: &str,
+    remaining: u16,
+    depth: u16,
+    line: &mut Vec<String>,
+) -> bool {
+    if normalized_fen(board) == target_fen {
+        return true;
+    }
+    if remaining == 0 || !material_reachable(board, target) {
+        return false;
+    }
+    let sign = match board.turn() {
+        pleco::Player::White => 1,
+        pleco::Player::Black => -1,
+    };
+    let mut moves: Vec<(BitMove, i32)> = board
+        .generate_moves()
+        .iter()
+        .map(|mov| {
+            board.apply_move(*mov);
+            let score = sign * evaluate_board(board, depth);
+            board.undo_move();
+            (*mov, score)
+        })
+        .collect();
+    moves.sort_by_key(|(_, score)| std::cmp::Reverse(*score));
+    for (mov, _) in moves {
+        board.apply_move(mov);
+        line.push(mov.stringify());
+        let found = search_line(board, target, target_fen, remaining - 1, depth, line);
+        board.undo_move();
+        if found {
+            return true;
+        }
+        line.pop();
+    }
+    false
+}
+
+/// Whether `target`'s material could still arise from `board`: pieces are never added and
+/// pawns never come back, and every extra queen, rook, bishop or knight needs a promotion
+fn material_reachable(board: &Board, target: &Board) -> bool {
+    [pleco::Player::White, pleco::Player::Black]
+        .into_iter()
+        .all(|player| {
+            let pawns = board.count_piece(player, pleco::PieceType::P);
+            let target_pawns = target.count_piece(player, pleco::PieceType::P);
+            let promotions: u8 = [
+                pleco::PieceType::N,
+                pleco::PieceType::B,
+                pleco::PieceType::R,
+                pleco::PieceType::Q,
+            ]
+            .into_iter()
+            .map(|piece| {
+                target
+                    .count_piece(player, piece)
+                    .saturating_sub(board.count_piece(player, piece))
+            })
+            .sum();
+            target.count_pieces_player(player) <= board.count_pieces_player(player)
+                && target_pawns + promotions <= pawns
+        })
+}
+
+/// FEN without the halfmove clock and fullmove number, so that positions which are the same
+/// for repetition purposes (placement, side to move, castling rights, en passant) compare equal
+fn normalized_fen(board: &Board) -> String {
+    board
+        .fen()
+        .split_whitespace()
+        .take(4)
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Splits PGN movetext into SAN tokens, dropping move numbers, comments, variations,
+/// NAGs and the result marker
+fn movetext_tokens(movetext: &str) -> Vec<String> {
+    movetext_entries(movetext)
+        .into_iter()
+        .map(|(san, _)| san)
+        .collect()
+}
+
+/// SAN tokens of a movetext with the comment following each move that holds a
+/// `[%clk h:mm:ss]` clock, if any, see `parse_clock_comment`
+fn movetext_entries(movetext: &str) -> Vec<(String, Option<String>)> {
+    let mut entries: Vec<(String, Option<String>)> = Vec::new();
+    let mut token = String::new();
+    let mut comment: Option<String> = None;
+    let mut variation_depth = 0;
+    let push_token = |token: &mut String, entries: &mut Vec<(String, Option<String>)>| {
+        let san = token.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.');
+        let is_result = matches!(token.as_str(), "1-0" | "0-1" | "1/2-1/2" | "*");
+        if !is_result && !san.is_empty() && !san.starts_with('$') {
+            entries.push((String::from(san), None));
+        }
+        token.clear();
+    };
+    for c in movetext.chars() {
+        if let Some(text) = comment.as_mut() {
+            if c == '}' {
+                if text.contains("[%clk") && variation_depth == 0 {
+                    if let Some(last) = entries.last_mut() {
+                        last.1 = Some(text.clone());
+                    }
+                }
+                comment = None;
+            } else {
+                text.push(c);
+            }
+            continue;
+        }
+        match c {
+            '{' => {
+                push_token(&mut token, &mut entries);
+                comment = Some(String::new());
+            }
+            '(' => {
+                push_token(&mut token, &mut entries);
+                variation_depth += 1;
+            }
+            ')' => variation_depth -= 1,
+            _ if variation_depth > 0 => {}
+            _ if c.is_whitespace() => push_token(&mut token, &mut entries),
+            _ => token.push(c),
+        }
+    }
+    push_token(&mut token, &mut entries);
+    entries
+}
+
+/// Milliseconds of the `[%clk h:mm:ss]` (optionally with fractional seconds) in a comment.
+/// Clocks that are malformed, negative or too large for a u32 are an `InvalidPgn` error.
+fn parse_clock_comment(comment: &str) -> Result<u32, ChessError> {
+    let invalid = || ChessError::InvalidPgn(format!("Invalid clock comment: {}", comment.trim()));
+    let clock = comment
+        .split("[%clk")
+        .nth(1)
+        .and_then(|clock| clock.split(']').next())
+        .ok_or_else(invalid)?
+        .trim();
+    let mut parts = clock.split(':').rev();
+    let seconds: f64 = parts
+        .next()
+        .and_then(|seconds| seconds.parse().ok())
+        .ok_or_else(invalid)?;
+    let minutes: u32 = parts.next().unwrap_or("0").parse().map_err(|_| invalid())?;
+    let hours: u32 = parts.next().unwrap_or("0").parse().map_err(|_| invalid())?;
+    let seconds_millis = (seconds * 1000.0).round();
+    if parts.next().is_some() || !(0.0..=u32::MAX as f64).contains(&seconds_millis) {
+        return Err(invalid());
+    }
+    hours
+        .checked_mul(3600)
+        .and_then(|total| total.checked_add(minutes.checked_mul(60)?))
+        .and_then(|total| total.checked_mul(1000))
+        .and_then(|total| total.checked_add(seconds_millis as u32))
+        .ok_or_else(invalid)
+}
+
+/// Packs a UCI move as origin square (bits 0-5), destination square (bits 6-11) and
+/// promotion piece (bits 12-14: 0 none, then n, b, r, q)
+fn pack_uci_move(uci_move: &str) -> u16 {
+    let square = |range: std::ops::Range<usize>| {
+        uci_move
+            .get(range)
+            .and_then(parse_square)
+            .map_or(0, |square| square.0 as u16)
+    };
+    let promotion = match uci_move.get(4..) {
+        Some("n") => 1,
+        Some("b") => 2,
+        Some("r") => 3,
+        Some("q") => 4,
+        _ => 0,
+    };
+    square(0..2) | square(2..4) << 6 | promotion << 12
+}
+
+fn unpack_uci_move(packed: u16) -> String {
+    let promotion = match packed >> 12 {
+        1 => "n",
+        2 => "b",
+        3 => "r",
+        4 => "q",
+        _ => "",
+    };
+    format!(
+        "{}{}{}",
+        square_name(pleco::SQ((packed & 63) as u8)),
+        square_name(pleco::SQ((packed >> 6 & 63) as u8)),
+        promotion
+    )
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl ByteReader<'_> {
+    fn take(&mut self, length: usize) -> Result<&[u8], ChessError> {
+        let end = self.position + length;
+        let slice = self
+            .bytes
+            .get(self.position..end)
+            .ok_or_else(|| ChessError::InvalidBinaryData(String::from("unexpected end of data")))?;
+        self.position = end;
+        Ok(slice)
+    }
+    fn is_at_end(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+    fn read_u8(&mut self) -> Result<u8, ChessError> {
+        Ok(self.take(1)?[0])
+    }
+    fn read_u16(&mut self) -> Result<u16, ChessError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    fn read_u32(&mut self) -> Result<u32, ChessError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn read_u64(&mut self) -> Result<u64, ChessError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// URL-safe base64 without padding
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (index, byte)| {
+            group | (*byte as u32) << (16 - 8 * index)
+        });
+        for index in 0..=chunk.len() {
+            encoded.push(BASE64_ALPHABET[(group >> (18 - 6 * index) & 63) as usize] as char);
+        }
+    }
+    encoded
+}
+
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 3 / 4);
+    for chunk in encoded.as_bytes().chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut group = 0u32;
+        for (index, c) in chunk.iter().enumerate() {
+            let value = BASE64_ALPHABET.iter().position(|symbol| symbol == c)? as u32;
+            group |= value << (18 - 6 * index);
+        }
+        for index in 0..chunk.len() - 1 {
+            bytes.push((group >> (16 - 8 * index)) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+const FILE_A: u64 = 0x0101_0101_0101_0101;
+const FILE_H: u64 = FILE_A << 7;
+const DARK_SQUARES: u64 = 0xAA55_AA55_AA55_AA55;
+
+/// Manhattan distance from each square to the nearest of d4, e4, d5 and e5
+#[rustfmt::skip]
+const CENTER_DISTANCE: [u8; 64] = [
+    6, 5, 4, 3, 3, 4, 5, 6,
+    5, 4, 3, 2, 2, 3, 4, 5,
+    4, 3, 2, 1, 1, 2, 3, 4,
+    3, 2, 1, 0, 0, 1, 2, 3,
+    3, 2, 1, 0, 0, 1, 2, 3,
+    4, 3, 2, 1, 1, 2, 3, 4,
+    5, 4, 3, 2, 2, 3, 4, 5,
+    6, 5, 4, 3, 3, 4, 5, 6,
+];
+
+/// Chebyshev distance, i.e. the number of king moves between two squares
+fn square_distance(a: pleco::SQ, b: pleco::SQ) -> u8 {
+    let file_distance = (a.0 % 8).abs_diff(b.0 % 8);
+    let rank_distance = (a.0 / 8).abs_diff(b.0 / 8);
+    file_distance.max(rank_distance)
+}
+
+/// Rejects a loaded position where the side that is not to move is in check, since the
+/// previous move would have left that king in check
+fn check_side_not_to_move(board: &Board) -> Result<(), ChessError> {
+    let king_square = board.king_sq(board.turn().other_player());
+    if attack_map(board, board.turn()) & (1 << king_square.0) != 0 {
+        Err(ChessError::SideNotToMoveInCheck)
+    } else {
+        Ok(())
+    }
+}
+
+/// Every square attacked by at least one of `player`'s pieces
+fn attack_map(board: &Board, player: pleco::Player) -> u64 {
+    let pieces = board.get_occupied_player(player).0;
+    (0..64)
+        .filter(|square| pieces & (1 << square) != 0)
+        .map(|square| {
+            let piece = board.piece_at_sq(pleco::SQ(square)).type_of();
+            board.attacks_from(piece, pleco::SQ(square), player).0
+        })
+        .fold(0, |map, attacks| map | attacks)
+}
+
+/// Whether neither side can possibly checkmate: K vs K, K+B vs K, K+N vs K, or K+B vs K+B
+/// with both bishops on the same square colour
+fn is_insufficient_material(board: &Board) -> bool {
+    let heavy_pieces = [
+        pleco::PieceType::P,
+        pleco::PieceType::R,
+        pleco::PieceType::Q,
+    ];
+    if heavy_pieces
+        .iter()
+        .any(|piece| board.piece_bb_both_players(*piece).0 != 0)
+    {
+        return false;
+    }
+    let knights = board.piece_bb_both_players(pleco::PieceType::N).0;
+    let bishops = board.piece_bb_both_players(pleco::PieceType::B).0;
+    match (knights.count_ones(), bishops.count_ones()) {
+        (0, 0) | (1, 0) | (0, 1) => true,
+        (0, 2) => {
+            let white_bishops = board.piece_bb(pleco::Player::White, pleco::PieceType::B).0;
+            let same_colour = bishops & DARK_SQUARES == bishops || bishops & DARK_SQUARES == 0;
+            white_bishops.count_ones() == 1 && same_colour
+        }
+        _ => false,
+    }
+}
+
+fn is_dark_square(square: pleco::SQ) -> bool {
+    (square.0 % 8 + square.0 / 8) & 1 == 0
+}
+
+/// Whether `strong` only has rook pawns on a single file (plus wrong bishops) and the lone
+/// defending king has reached the promotion corner
+fn is_rook_pawn_fortress(board: &Board, strong: pleco::Player) -> bool {
+    let weak = strong.other_player();
+    if board.get_occupied_player(weak).count_bits() != 1 {
+        return false;
+    }
+    let pawns = board.piece_bb(strong, pleco::PieceType::P).0;
+    let bishops = board.piece_bb(strong, pleco::PieceType::B).0;
+    let king = board.piece_bb(strong, pleco::PieceType::K).0;
+    if pawns == 0 || pawns | bishops | king != board.get_occupied_player(strong).0 {
+        return false;
+    }
+    let file = if pawns & FILE_A == pawns {
+        0
+    } else if pawns & FILE_H == pawns {
+        7
+    } else {
+        return false;
+    };
+    let promotion_square = match strong {
+        pleco::Player::White => pleco::SQ(56 + file),
+        pleco::Player::Black => pleco::SQ(file),
+    };
+    let wrong_bishops = (0..64)
+        .filter(|square| bishops & (1 << square) != 0)
+        .all(|square| is_dark_square(pleco::SQ(square)) != is_dark_square(promotion_square));
+    wrong_bishops && square_distance(board.king_sq(weak), promotion_square) <= 1
+}
+
+/// Curated opening table compiled into the binary, one `eco<TAB>name<TAB>SAN movetext` per line
+const OPENINGS_TSV: &str = include_str!("openings.tsv");
+
+struct OpeningLine {
+    name: String,
+    uci_moves: Vec<String>,
+}
+
+/// Parses the embedded opening table, resolving each line's SAN from the start position
+fn opening_lines() -> Vec<OpeningLine> {
+    OPENINGS_TSV
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut columns = line.split('\t');
+            let (_eco, name, movetext) = (columns.next()?, columns.next()?, columns.next()?);
+            let mut board = Board::start_pos();
+            let mut uci_moves = Vec::new();
+            for san in movetext_tokens(movetext) {
+                let mov = *san_candidates(&board, &san).first()?;
+                uci_moves.push(mov.stringify());
+                board.apply_move(mov);
+            }
+            Some(OpeningLine {
+                name: String::from(name),
+                uci_moves,
+            })
+        })
+        .collect()
+}
+
+/// Lowercases the promotion suffix of a UCI move, leaving anything else untouched
+fn normalize_uci_move(mut uci_move: String) -> String {
+    if uci_move.len() == 5 && uci_move.is_char_boundary(4) {
+        uci_move[4..].make_ascii_lowercase();
+    }
+    uci_move
+}
+
+/// Whether `uci_move` looks like a UCI move: two squares and an optional promotion piece
+fn is_uci_move_syntax(uci_move: &str) -> bool {
+    let square = |range: std::ops::Range<usize>| uci_move.get(range).and_then(parse_square);
+    square(0..2).is_some()
+        && square(2..4).is_some()
+        && matches!(uci_move.get(4..), Some("" | "n" | "b" | "r" | "q"))
+}
+
+fn parse_square(square: &str) -> Option<pleco::SQ> {
+    match square.as_bytes() {
+        [file @ b'a'..=b'h', rank @ b'1'..=b'8'] => {
+            Some(pleco::SQ((rank - b'1') * 8 + (file - b'a')))
+        }
+        _ => None,
+    }
+}
+
+fn square_name(square: pleco::SQ) -> String {
+    let file = (b'a' + square.0 % 8) as char;
+    let rank = (b'1' + square.0 / 8) as char;
+    format!("{}{}", file, rank)
+}
+
+/// Figurine for a FEN piece letter, the letter itself when it is not one
+fn figurine(letter: char) -> char {
+    match letter {
+        'K' => '♔',
+        'Q' => '♕',
+        'R' => '♖',
+        'B' => '♗',
+        'N' => '♘',
+        'P' => '♙',
+        'k' => '♚',
+        'q' => '♛',
+        'r' => '♜',
+        'b' => '♝',
+        'n' => '♞',
+        'p' => '♟',
+        _ => letter,
+    }
+}
+
+fn piece_letter(piece: pleco::PieceType) -> char {
+    match piece {
+        pleco::PieceType::N => 'N',
+        pleco::PieceType::B => 'B',
+        pleco::PieceType::R => 'R',
+        pleco::PieceType::Q => 'Q',
+        pleco::PieceType::K => 'K',
+        _ => 'P',
+    }
+}
+
+/// SAN of a legal move played from `board`, with the minimal disambiguation and a `+` or `#`
+/// suffix when the move gives check or mate
+fn move_to_san(board: &Board, mov: BitMove) -> String {
+    let mut san = String::new();
+    if mov.is_king_castle() {
+        san.push_str("O-O");
+    } else if mov.is_queen_castle() {
+        san.push_str("O-O-O");
+    } else {
+        let source = mov.get_src();
+        let destination = mov.get_dest();
+        let piece = board.piece_at_sq(source).type_of();
+        if piece == pleco::PieceType::P {
+            if mov.is_capture() {
+                san.push((b'a' + source.0 % 8) as char);
+                san.push('x');
+            }
+            san.push_str(&square_name(destination));
+            if mov.is_promo() {
+                san.push('=');
+                san.push(piece_letter(mov.promo_piece()));
+            }
+        } else {
+            san.push(piece_letter(piece));
+            let rivals: Vec<pleco::SQ> = board
+                .generate_moves()
+                .iter()
+                .filter(|other| {
+                    !other.is_castle()
+                        && other.get_dest() == destination
+                        && other.get_src() != source
+                        && board.piece_at_sq(other.get_src()).type_of() == piece
+                })
+                .map(|other| other.get_src())
+                .collect();
+            if !rivals.is_empty() {
+                let file = (b'a' + source.0 % 8) as char;
+                let rank = (b'1' + source.0 / 8) as char;
+                if rivals.iter().all(|rival| rival.0 % 8 != source.0 % 8) {
+                    san.push(file);
+                } else if rivals.iter().all(|rival| rival.0 / 8 != source.0 / 8) {
+                    san.push(rank);
+                } else {
+                    san.push(file);
+                    san.push(rank);
+                }
+            }
+            if mov.is_capture() {
+                san.push('x');
+            }
+            san.push_str(&square_name(destination));
+        }
+    }
+    let mut after = board.clone();
+    after.apply_move(mov);
+    if after.checkmate() {
+        san.push('#');
+    } else if after.in_check() {
+        san.push('+');
+    }
+    san
+}
+
+fn piece_type_from_char(c: char) -> Option<pleco::PieceType> {
+    match c.to_ascii_uppercase() {
+        'P' => Some(pleco::PieceType::P),
+        'N' => Some(pleco::PieceType::N),
+        'B' => Some(pleco::PieceType::B),
+        'R' => Some(pleco::PieceType::R),
+        'Q' => Some(pleco::PieceType::Q),
+        'K' => Some(pleco::PieceType::K),
+        _ => None,
+    }
+}
+
+/// Legal moves matching a SAN string. More than one candidate means the SAN is ambiguous,
+/// none means it is illegal or malformed.
+fn san_candidates(board: &Board, san: &str) -> Vec<BitMove> {
+    let san = san.trim_end_matches(['+', '#', '!', '?']);
+    let legal_moves = board.generate_moves();
+    match san {
+        "O-O" | "0-0" => {
+            return legal_moves
+                .iter()
+                .filter(|m| m.is_king_castle())
+                .cloned()
+                .collect()
+        }
+        "O-O-O" | "0-0-0" => {
+            return legal_moves
+                .iter()
+                .filter(|m| m.is_queen_castle())
+                .cloned()
+                .collect()
+        }
+        _ => {}
+    }
+    let (san, promotion) = match san.split_once('=') {
+        Some((san, promotion)) => (san, promotion.chars().next()),
+        None => match san.chars().last() {
+            Some(c) if san.len() > 2 && "NBRQ".contains(c) => (&san[..san.len() - 1], Some(c)),
+            _ => (san, None),
+        },
+    };
+    let promotion = match promotion.map(piece_type_from_char) {
+        Some(None) => return Vec::new(),
+        Some(Some(piece)) => Some(piece),
+        None => None,
+    };
+    let mut chars: Vec<char> = san.chars().filter(|c| *c != 'x').collect();
+    let piece = match chars.first() {
+        Some(c) if c.is_ascii_uppercase() => match piece_type_from_char(chars.remove(0)) {
+            Some(piece) => piece,
+            None => return Vec::new(),
+        },
+        _ => pleco::PieceType::P,
+    };
+    if chars.len() < 2 {
+        return Vec::new();
+    }
+    let destination: String = chars[chars.len() - 2..].iter().collect();
+    let destination = match parse_square(&destination) {
+        Some(square) => square,
+        None => return Vec::new(),
+    };
+    let disambiguation = &chars[..chars.len() - 2];
+    legal_moves
+        .iter()
+        .filter(|m| {
+            let source = m.get_src();
+            !m.is_castle()
+                && m.get_dest() == destination
+                && board.piece_at_sq(source).type_of() == piece
+                && disambiguation.iter().all(|c| match c {
+                    'a'..='h' => source.0 % 8 == *c as u8 - b'a',
+                    '1'..='8' => source.0 / 8 == *c as u8 - b'1',
+                    _ => false,
+                })
+                && match promotion {
+                    Some(promotion) => m.is_promo() && m.promo_piece() == promotion,
+                    None => !m.is_promo(),
+                }
+        })
+        .cloned()
+        .collect()
+}
+
+/// Deepest search the engine helpers will run
+const MAX_SEARCH_DEPTH: u16 = 4;
+const MATE_SCORE: i32 = 100_000;
+/// Number of top moves and search depth behind `ChessGame::complexity`
+const COMPLEXITY_MOVES: usize = 5;
+const COMPLEXITY_DEPTH: u16 = 1;
+
+fn piece_value(piece: pleco::PieceType) -> i32 {
+    match piece {
+        pleco::PieceType::P => 100,
+        pleco::PieceType::N => 320,
+        pleco::PieceType::B => 330,
+        pleco::PieceType::R => 500,
+        pleco::PieceType::Q => 900,
+        _ => 0,
+    }
+}
+
+/// Material balance in centipawns, positive favouring white
+fn material_score(board: &Board) -> i32 {
+    let mut score = 0;
+    for piece in [
+        pleco::PieceType::P,
+        pleco::PieceType::N,
+        pleco::PieceType::B,
+        pleco::PieceType::R,
+        pleco::PieceType::Q,
+    ] {
+        let white = board.count_piece(pleco::Player::White, piece) as i32;
+        let black = board.count_piece(pleco::Player::Black, piece) as i32;
+        score += (white - black) * piece_value(piece);
+    }
+    score
+}
+
+fn perft_board(board: &mut Board, depth: u16) -> u64 {
+    if depth == 0 {
+        return 1;
+    }
+    let moves = board.generate_moves();
+    if depth == 1 {
+        return moves.len() as u64;
+    }
+    let mut nodes = 0;
+    for mov in moves.iter() {
+        board.apply_move(*mov);
+        nodes += perft_board(board, depth - 1);
+        board.undo_move();
+    }
+    nodes
+}
+
+fn evaluate_board(board: &Board, depth: u16) -> i32 {
+    let mut board = board.clone();
+    let score = search(
+        &mut board,
+        depth.min(MAX_SEARCH_DEPTH),
+        -MATE_SCORE * 2,
+        MATE_SCORE * 2,
+    );
+    match board.turn() {
+        pleco::Player::White => score,
+        pleco::Player::Black => -score,
+    }
+}
+
+/// Game phase at the start position, see `ChessGame::tapered_eval`
+const MAX_PHASE: i32 = 24;
+
+/// Middlegame and endgame piece values in centipawns
+fn tapered_piece_values(piece: pleco::PieceType) -> (i32, i32) {
+    match piece {
+        pleco::PieceType::P => (82, 94),
+        pleco::PieceType::N => (337, 281),
+        pleco::PieceType::B => (365, 297),
+        pleco::PieceType::R => (477, 512),
+        pleco::PieceType::Q => (1025, 936),
+        _ => (0, 0),
+    }
+}
+
+fn phase_weight(piece: pleco::PieceType) -> i32 {
+    match piece {
+        pleco::PieceType::N | pleco::PieceType::B => 1,
+        pleco::PieceType::R => 2,
+        pleco::PieceType::Q => 4,
+        _ => 0,
+    }
+}
+
+// Piece-square tables from white's point of view, laid out as seen from white's side with
+// a8 first and h1 last. Pieces other than the king use the same table in both phases.
+#[rustfmt::skip]
+const PAWN_TABLE: [i32; 64] = [
+     0,  0,  0,  0,  0,  0,  0,  0,
+    50, 50, 50, 50, 50, 50, 50, 50,
+    10, 10, 20, 30, 30, 20, 10, 10,
+     5,  5, 10, 25, 25, 10,  5,  5,
+     0,  0,  0, 20, 20,  0,  0,  0,
+     5, -5,-10,  0,  0,-10, -5,  5,
+     5, 10, 10,-20,-20, 10, 10,  5,
+     0,  0,  0,  0,  0,  0,  0,  0,
+];
+#[rustfmt::skip]
+const KNIGHT_TABLE: [i32; 64] = [
+    -50,-40,-30,-30,-30,-30,-40,-50,
+    -40,-20,  0,  0,  0,  0,-20,-40,
+    -30,  0, 10, 15, 15, 10,  0,-30,
+    -30,  5, 15, 20, 20, 15,  5,-30,
+    -30,  0, 15, 20, 20, 15,  0,-30,
+    -30,  5, 10, 15, 15, 10,  5,-30,
+    -40,-20,  0,  5,  5,  0,-20,-40,
+    -50,-40,-30,-30,-30,-30,-40,-50,
+];
+#[rustfmt::skip]
+const BISHOP_TABLE: [i32; 64] = [
+    -20,-10,-10,-10,-10,-10,-10,-20,
+    -10,  0,  0,  0,  0,  0,  0,-10,
+    -10,  0,  5, 10, 10,  5,  0,-10,
+    -10,  5,  5, 10, 10,  5,  5,-10,
+    -10,  0, 10, 10, 10, 10,  0,-10,
+    -10, 10, 10, 10, 10, 10, 10,-10,
+    -10,  5,  0,  0,  0,  0,  5,-10,
+    -20,-10,-10,-10,-10,-10,-10,-20,
+];
+#[rustfmt::skip]
+const ROOK_TABLE: [i32; 64] = [
+     0,  0,  0,  0,  0,  0,  0,  0,
+     5, 10, 10, 10, 10, 10, 10,  5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+    -5,  0,  0,  0,  0,  0,  0, -5,
+     0,  0,  0,  5,  5,  0,  0,  0,
+];
+#[rustfmt::skip]
+const QUEEN_TABLE: [i32; 64] = [
+    -20,-10,-10, -5, -5,-10,-10,-20,
+    -10,  0,  0,  0,  0,  0,  0,-10,
+    -10,  0,  5,  5,  5,  5,  0,-10,
+     -5,  0,  5,  5,  5,  5,  0, -5,
+      0,  0,  5,  5,  5,  5,  0, -5,
+    -10,  5,  5,  5,  5,  5,  0,-10,
+    -10,  0,  5,  0,  0,  0,  0,-10,
+    -20,-10,-10, -5, -5,-10,-10,-20,
+];
+#[rustfmt::skip]
+const KING_MIDDLEGAME_TABLE: [i32; 64] = [
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -30,-40,-40,-50,-50,-40,-40,-30,
+    -20,-30,-30,-40,-40,-30,-30,-20,
+    -10,-20,-20,-20,-20,-20,-20,-10,
+     20, 20,  0,  0,  0,  0, 20, 20,
+     20, 30, 10,  0,  0, 10, 30, 20,
+];
+#[rustfmt::skip]
+const KING_ENDGAME_TABLE: [i32; 64] = [
+    -50,-40,-30,-20,-20,-30,-40,-50,
+    -30,-20,-10,  0,  0,-10,-20,-30,
+    -30,-10, 20, 30, 30, 20,-10,-30,
+    -30,-10, 30, 40, 40, 30,-10,-30,
+    -30,-10, 30, 40, 40, 30,-10,-30,
+    -30,-10, 20, 30, 30, 20,-10,-30,
+    -30,-30,  0,  0,  0,  0,-30,-30,
+    -50,-30,-30,-30,-30,-30,-30,-50,
+];
+
+/// Middlegame and endgame piece-square bonus for `piece` of `player` on `square`
+fn piece_square_bonus(piece: pleco::PieceType, player: pleco::Player, square: u8) -> (i32, i32) {
+    let rank = match player {
+        pleco::Player::White => square / 8,
+        pleco::Player::Black => 7 - square / 8,
+    };
+    let index = ((7 - rank) * 8 + square % 8) as usize;
+    match piece {
+        pleco::PieceType::P => (PAWN_TABLE[index], PAWN_TABLE[index]),
+        pleco::PieceType::N => (KNIGHT_TABLE[index], KNIGHT_TABLE[index]),
+        pleco::PieceType::B => (BISHOP_TABLE[index], BISHOP_TABLE[index]),
+        pleco::PieceType::R => (ROOK_TABLE[index], ROOK_TABLE[index]),
+        pleco::PieceType::Q => (QUEEN_TABLE[index], QUEEN_TABLE[index]),
+        pleco::PieceType::K => (KING_MIDDLEGAME_TABLE[index], KING_ENDGAME_TABLE[index]),
+        _ => (0, 0),
+    }
+}
+
+fn tapered_score(board: &Board) -> i32 {
+    let mut middlegame = 0;
+    let mut endgame = 0;
+    let mut phase = 0;
+    for player in [pleco::Player::White, pleco::Player::Black] {
+        let sign = match player {
+            pleco::Player::White => 1,
+            pleco::Player::Black => -1,
+        };
+        for piece in [
+            pleco::PieceType::P,
+            pleco::PieceType::N,
+            pleco::PieceType::B,
+            pleco::PieceType::R,
+            pleco::PieceType::Q,
+            pleco::PieceType::K,
+        ] {
+            let (middlegame_value, endgame_value) = tapered_piece_values(piece);
+            let mut pieces = board.piece_bb(player, piece).0;
+            while pieces != 0 {
+                let square = pieces.trailing_zeros() as u8;
+                pieces &= pieces - 1;
+                let (middlegame_bonus, endgame_bonus) = piece_square_bonus(piece, player, square);
+                middlegame += sign * (middlegame_value + middlegame_bonus);
+                endgame += sign * (endgame_value + endgame_bonus);
+                phase += phase_weight(piece);
+            }
+        }
+    }
+    let phase = phase.min(MAX_PHASE);
+    (middlegame * phase + endgame * (MAX_PHASE - phase)) / MAX_PHASE
+}
+
+/// Legal move with the best `search` score for the side to move, searching `depth` plies
+/// including the move itself (at least one). `None` when there are no legal moves.
+fn best_move(board: &Board, depth: u16) -> Option<BitMove> {
+    let depth = depth.clamp(1, MAX_SEARCH_DEPTH);
+    let mut board = board.clone();
+    let mut best: Option<(BitMove, i32)> = None;
+    for mov in board.generate_moves().iter() {
+        board.apply_move(*mov);
+        let score = -search(&mut board, depth - 1, -MATE_SCORE * 2, MATE_SCORE * 2);
+        board.undo_move();
+        if best.is_none_or(|(_, best_score)| score > best_score) {
+            best = Some((*mov, score));
+        }
+    }
+    best.map(|(mov, _)| mov)
+}
+
+/// Alpha-beta negamax returning the score from the side to move's point of view.
+/// Mates found with more depth remaining (i.e. sooner) score further from zero.
+fn search(board: &mut Board, depth: u16, mut alpha: i32, beta: i32) -> i32 {
+    let moves = board.generate_moves();
+    if moves.is_empty() {
+        return if board.in_check() {
+            -(MATE_SCORE + depth as i32)
+        } else {
+            0
+        };
+    }
+    if depth == 0 {
+        return match board.turn() {
+            pleco::Player::White => material_score(board),
+            pleco::Player::Black => -material_score(board),
+        };
+    }
+    for mov in moves.iter() {
+        board.apply_move(*mov);
+        let score = -search(board, depth - 1, -beta, -alpha);
+        board.undo_move();
+        if score >= beta {
+            return score;
+        }
+        if score > alpha {
+            alpha = score;
+        }
+    }
+    alpha
+}
+
+pub struct ChessGameBuilder {
+    initial_board: Board,
+    moves: Vec<Move>,
+    time_limit: u32, // in milliseconds
+    time_control: TimeControl,
+    timeout_insufficient_material_is_draw: bool,
+    draw_claim_window: Option<u16>, // in plies
+    auto_draw_on_insufficient_material: bool,
+    auto_first_move_time: bool,
+    black_time_limit: Option<u32>, // in milliseconds, overrides time_limit for black
+    white_name: Option<String>,
+    black_name: Option<String>,
+    event: Option<String>,
+    site: Option<String>,
+    date: Option<String>,
+    round: Option<String>,
+    time_control_stages: Vec<TimeControlStage>, // empty unless built with stages
+    chess960_castling: Option<String>,
+}
+// Get Time since epoch in miliseconds
+// let now = SystemTime::now();
+// let since_the_epoch = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
+// println!("Time since the epoch: {:?}", since_the_epoch);
+impl ChessGameBuilder {
+    pub fn new() -> ChessGameBuilder {
+        ChessGameBuilder {
+            initial_board: Board::start_pos(),
+            moves: Vec::new(),
+            time_limit: 0,
+            time_control: TimeControl::SuddenDeath,
+            timeout_insufficient_material_is_draw: true,
+            draw_claim_window: None,
+            auto_draw_on_insufficient_material: true,
+            auto_first_move_time: false,
+            black_time_limit: None,
+            white_name: None,
+            black_name: None,
+            event: None,
+            site: None,
+            date: None,
+            round: None,
+            time_control_stages: Vec::new(),
+            chess960_castling: None,
+        }
+    }
+    pub fn with_initial_board(mut self, board: Board) -> ChessGameBuilder {
+        self.initial_board = board;
+        self.chess960_castling = None;
+        self
+    }
+    /// Sets the initial board from a FEN string, failing with `ChessError::InvalidFen` when
+    /// pleco cannot read it. On top of pleco's own checks, positions where the side that is not
+    /// to move is in check are rejected with `ChessError::SideNotToMoveInCheck`, since the
+    /// previous move would have left that king in check.
+    ///
+    /// A Shredder-FEN castling field naming the rooks' files, e.g. "HFhf", gives Chess960
+    /// castling: the king lands on the g or c file and the rook next to it on the inside, and
+    /// the move is written as the king taking its own rook in UCI (e.g. "g1h1") and "O-O" or
+    /// "O-O-O" in SAN. pleco cannot castle those, so the engine helpers such as `perft` and
+    /// `evaluate` leave such castles out.
+    pub fn with_fen(mut self, fen: &str) -> Result<ChessGameBuilder, ChessError> {
+        let (board, chess960_castling) = board_from_fen(fen)?;
+        check_side_not_to_move(&board)?;
+        self.initial_board = board;
+        self.chess960_castling = chess960_castling;
+        Ok(self)
+    }
+    /// Starts from Chess960 position `position_id` (see `chess960_fen`) with castling by the
+    /// Chess960 rules (see `with_fen`). Ids above 959 return
+    /// `ChessError::InvalidChess960Position`.
+    pub fn with_chess960(self, position_id: u16) -> Result<ChessGameBuilder, ChessError> {
+        let fen =
+            chess960_fen(position_id).ok_or(ChessError::InvalidChess960Position(position_id))?;
+        self.with_fen(&fen)
+    }
+    /// Only allow draw claims within `plies` half-moves of the move that created the
+    /// condition, see `ChessGame::can_claim_draw`
+    pub fn with_draw_claim_window(mut self, plies: u16) -> ChessGameBuilder {
+        self.draw_claim_window = Some(plies);
+        self
+    }
+    /// Whether the game is drawn automatically as soon as neither side has mating material
+    /// (FIDE, the default) rather than playing on until a claim or a clock runs out
+    pub fn with_auto_draw_on_insufficient_material(mut self, enabled: bool) -> ChessGameBuilder {
+        self.auto_draw_on_insufficient_material = enabled;
+        self
+    }
+    /// Charge the first move with the time since the game was built when it is played
+    /// without a `time_taken`, see `ChessGame::play_move_at`
+    pub fn with_auto_first_move_time(mut self, enabled: bool) -> ChessGameBuilder {
+        self.auto_first_move_time = enabled;
+        self
+    }
+    pub fn with_time_limit(mut self, time_limit: u32) -> ChessGameBuilder {
+        self.time_limit = time_limit;
+        self
+    }
+    /// Fischer increment in milliseconds, 0 for sudden death
+    pub fn with_increment(mut self, increment: u32) -> ChessGameBuilder {
+        self.time_control = TimeControl::from_increment(increment);
+        self
+    }
+    pub fn with_time_control(mut self, time_control: TimeControl) -> ChessGameBuilder {
+        self.time_control = time_control;
+        self
+    }
+    pub fn with_white_name(mut self, name: &str) -> ChessGameBuilder {
+        self.white_name = Some(String::from(name));
+        self
+    }
+    pub fn with_black_name(mut self, name: &str) -> ChessGameBuilder {
+        self.black_name = Some(String::from(name));
+        self
+    }
+    pub fn with_event(mut self, event: &str) -> ChessGameBuilder {
+        self.event = Some(String::from(event));
+        self
+    }
+    pub fn with_site(mut self, site: &str) -> ChessGameBuilder {
+        self.site = Some(String::from(site));
+        self
+    }
+    /// Date the game was played, in PGN form ("YYYY.MM.DD", unknown parts as "??")
+    pub fn with_date(mut self, date: &str) -> ChessGameBuilder {
+        self.date = Some(String::from(date));
+        self
+    }
+    pub fn with_round(mut self, round: &str) -> ChessGameBuilder {
+        self.round = Some(String::from(round));
+        self
+    }
+    /// Gives black a different base time than `time_limit`, for odds games
+    pub fn with_black_time_limit(mut self, time_limit: u32) -> ChessGameBuilder {
+        self.black_time_limit = Some(time_limit);
+        self
+    }
+    /// Sets `time_limit` and a Fischer increment from "base+increment" or "base|increment"
+    /// notation: minutes and seconds, e.g. "3+2" and "5|0", or milliseconds when suffixed
+    /// with "ms", e.g. "180000ms+2000ms"
+    pub fn with_time_control_str(mut self, notation: &str) -> Result<ChessGameBuilder, ChessError> {
+        let invalid = || ChessError::InvalidTimeControl(String::from(notation));
+        let (base, increment) = notation.trim().split_once(['+', '|']).ok_or_else(invalid)?;
+        let millis = |part: &str, unit: u32| match part.trim().strip_suffix("ms") {
+            Some(millis) => millis.parse::<u32>().ok(),
+            None => part.trim().parse::<u32>().ok()?.checked_mul(unit),
+        };
+        self.time_limit = millis(base, 60 * 1000).ok_or_else(invalid)?;
+        self.time_control =
+            TimeControl::from_increment(millis(increment, 1000).ok_or_else(invalid)?);
+        Ok(self)
+    }
+    /// Tournament time control in stages, e.g. 40 moves in 90 minutes then 30 minutes for
+    /// the rest. The first stage sets `time_limit`; each later stage's base time is added to
+    /// a player's clock once they complete the previous stage's moves, and every move gets
+    /// the Fischer increment of its stage.
+    pub fn with_time_control_stages(mut self, stages: Vec<TimeControlStage>) -> ChessGameBuilder {
+        if let Some(first) = stages.first() {
+            self.time_limit = first.base_time;
+            self.time_control = TimeControl::from_increment(first.increment);
+        }
+        self.time_control_stages = stages;
+        self
+    }
+    /// Sets `time_limit` and a Fischer increment from a common time control
+    pub fn with_preset(mut self, preset: TimeControlPreset) -> ChessGameBuilder {
+        let (minutes, increment_seconds) = match preset {
+            TimeControlPreset::Bullet1_0 => (1, 0),
+            TimeControlPreset::Blitz3_2 => (3, 2),
+            TimeControlPreset::Blitz5_0 => (5, 0),
+            TimeControlPreset::Rapid10_0 => (10, 0),
+            TimeControlPreset::Rapid15_10 => (15, 10),
+            TimeControlPreset::Classical30_20 => (30, 20),
+        };
+        self.time_limit = minutes * 60 * 1000;
+        self.time_control = TimeControl::from_increment(increment_seconds * 1000);
+        self
+    }
+    /// Whether running out of time against a lone king is a draw (FIDE, the default)
+    /// or a loss for the player who flagged
+    pub fn with_timeout_insufficient_material_is_draw(mut self, is_draw: bool) -> ChessGameBuilder {
+        self.timeout_insufficient_material_is_draw = is_draw;
+        self
+    }
+    /// Appends the moves of a SAN movetext fragment (no tag pairs), e.g. "1. e4 e5 2. Nf3 Nc6".
+    /// Moves are resolved against the initial board, so call this after `with_initial_board`.
+    /// Comments, variations, NAGs and result markers are skipped.
+    pub fn with_pgn_movetext(mut self, movetext: &str) -> Result<ChessGameBuilder, Error> {
+        let mut board = self.initial_board.clone();
+        let mut castling = self.chess960_castling.clone();
+        for mov in self.moves.iter() {
+            apply_game_move_with_castling(&mut board, &mut castling, mov);
+        }
+        for san in movetext_tokens(movetext) {
+            let candidates = san_candidates_uci(&board, castling.as_deref(), &san);
+            match candidates.len() {
+                1 => {
+                    let mov = play_legal_move(&mut board, &mut castling, &candidates[0], 0)
+                        .expect("SAN candidates are legal moves");
+                    self.moves.push(mov);
+                }
+                0 => {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        format!("Illegal move in movetext: {}", san),
+                    ))
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        format!("Ambiguous move in movetext: {}", san),
+                    ))
+                }
+            }
+        }
+        Ok(self)
+    }
+    pub fn build(self) -> ChessGame {
+        let now = SystemTime::now();
+        ChessGame {
+            initial_board: self.initial_board,
+            moves: self.moves,
+            start_time: now
+                .duration_since(std::time::UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_millis() as u64,
+            time_limit: self.time_limit,
+            time_control: self.time_control,
+            timeout_insufficient_material_is_draw: self.timeout_insufficient_material_is_draw,
+            draw_claim_window: self.draw_claim_window,
+            auto_draw_on_insufficient_material: self.auto_draw_on_insufficient_material,
+            auto_first_move_time: self.auto_first_move_time,
+            black_time_limit: self.black_time_limit,
+            white_name: self.white_name,
+            black_name: self.black_name,
+            event: self.event,
+            site: self.site,
+            date: self.date,
+            round: self.round,
+            time_control_stages: self.time_control_stages,
+            result_override: None,
+            chess960_castling: self.chess960_castling,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Move {
+    uci_move: String,
+    time_taken: u32, // in milliseconds
+    // the move in the position it was played in, so replays skip parsing the UCI string
+    #[serde(skip)]
+    bit_move: Option<BitMove>,
+    #[serde(default)]
+    comment: Option<String>,
+    #[serde(default)]
+    nag: Option<u8>, // numeric annotation glyph, e.g. 1 for "!"
+}
+impl Move {
+    /// A promotion suffix is accepted in either case and stored lowercase, e.g. "e7e8Q"
+    /// becomes "e7e8q"
+    pub fn new(uci_move: String, time_taken: u32) -> Move {
+        Move {
+            uci_move: normalize_uci_move(uci_move),
+            time_taken,
+            comment: None,
+            nag: None,
+            bit_move: None,
+        }
+    }
+    /// A move already resolved against the position it is played in
+    fn resolved(bit_move: BitMove, time_taken: u32) -> Move {
+        Move {
+            bit_move: Some(bit_move),
+            ..Move::new(bit_move.stringify(), time_taken)
+        }
+    }
+    /// The move's `BitMove` in `board`, the position it is played from: the resolved one when
+    /// there is one, otherwise looked up among the legal moves by its UCI string
+    fn bit_move_on(&self, board: &Board) -> Option<BitMove> {
+        self.bit_move
+            .or_else(|| find_legal_move(board, &self.uci_move))
+    }
+    /// The move's SAN in `board`, the position it is played from, see `move_to_san`
+    fn san_on(&self, board: &Board) -> Option<String> {
+        match self.bit_move_on(board) {
+            Some(bit_move) => Some(move_to_san(board, bit_move)),
+            None => chess960_castle_san(board, &self.uci_move),
+        }
+    }
+    pub fn with_comment(mut self, comment: &str) -> Move {
+        self.comment = Some(String::from(comment));
+        self
+    }
+    /// Numeric annotation glyph, exported as "$nag", e.g. 1 for a good move, 2 for a mistake
+    pub fn with_nag(mut self, nag: u8) -> Move {
+        self.nag = Some(nag);
+        self
+    }
+    /// Checks that the move is written as UCI: two squares from a1 to h8 followed by an
+    /// optional lowercase promotion piece (q, r, b or n)
+    pub fn validate_uci(&self) -> Result<(), ChessError> {
+        if is_uci_move_syntax(&self.uci_move) {
+            Ok(())
+        } else {
+            Err(ChessError::InvalidUci(self.uci_move.clone()))
+        }
+    }
+    pub fn uci_move(&self) -> &str {
+        &self.uci_move
+    }
+    pub fn time_taken(&self) -> u32 {
+        self.time_taken
+    }
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+    pub fn nag(&self) -> Option<u8> {
+        self.nag
+    }
+}
+
+/// What `ChessGame::sync_against` sends to a reconnecting client
+pub enum SyncResult {
+    /// Moves played since the client's last known position
+    Delta(Vec<Move>),
+    /// The client's position is not part of the game, so it gets the full game
+    Snapshot(ChessGame),
+}
+
+/// How a game is heading for a draw, see `ChessGame::drawing_mechanism`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrawMechanism {
+    PerpetualCheck,
+    PositionalRepetition,
+    Fortress,
+}
+
+/// The most recent move of a game, see `ChessGame::last_move_info`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MoveInfo {
+    pub san: String,
+    /// En passant included
+    pub is_capture: bool,
+    /// Checkmate included
+    pub is_check: bool,
+    pub is_castle: bool,
+    pub is_promotion: bool,
+}
+
+/// Errors from playing or undoing moves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChessError {
+    IllegalMove {
+        uci: String,
+    },
+    NoMovesToUndo,
+    /// The game already has a result, see `ChessGame::result`
+    GameOver,
+    InvalidUci(String),
+    /// SAN that matches no legal move in the position
+    IllegalSan(String),
+    /// PGN that cannot be read, with a description of the problem
+    InvalidPgn(String),
+    /// SAN that matches several legal moves, e.g. "Nd2" with knights on b1 and f3
+    AmbiguousSan(String),
+    /// Time control notation that cannot be read, see `ChessGameBuilder::with_time_control_str`
+    InvalidTimeControl(String),
+    /// Chess960 starting position numbers go from 0 to 959
+    InvalidChess960Position(u16),
+    /// FEN that pleco cannot read, with its description of the problem
+    InvalidFen(String),
+    /// A loaded position where the side that is not to move is in check
+    SideNotToMoveInCheck,
+    /// Data `ChessGame::from_bytes` cannot decode, with a description of the problem
+    InvalidBinaryData(String),
+    /// A value too long for its length field in `ChessGame::to_bytes`, naming the value
+    TooLargeForBinary(String),
+}
+
+impl std::fmt::Display for ChessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChessError::IllegalMove { uci } => write!(f, "Tried playing an illegal move: {}", uci),
+            ChessError::NoMovesToUndo => {
+                write!(f, "Tried undoing a move when there are no moves to undo")
+            }
+            ChessError::GameOver => write!(f, "Tried playing a move after the game ended"),
+            ChessError::InvalidUci(uci) => write!(f, "Invalid UCI move: {}", uci),
+            ChessError::IllegalSan(san) => write!(f, "Illegal SAN move: {}", san),
+            ChessError::InvalidPgn(reason) => write!(f, "Invalid PGN: {}", reason),
+            ChessError::AmbiguousSan(san) => write!(f, "Ambiguous SAN move: {}", san),
+            ChessError::InvalidTimeControl(notation) => {
+                write!(f, "Invalid time control: {}", notation)
+            }
+            ChessError::InvalidChess960Position(position_id) => {
+                write!(f, "Invalid Chess960 position: {}", position_id)
+            }
+            ChessError::InvalidFen(reason) => write!(f, "Invalid fen: {}", reason),
+            ChessError::SideNotToMoveInCheck => {
+                write!(f, "Invalid fen: the side not to move is in check")
+            }
+            ChessError::InvalidBinaryData(reason) => {
+                write!(f, "Invalid binary game data: {}", reason)
+            }
+            ChessError::TooLargeForBinary(value) => {
+                write!(f, "{} too long for the binary encoding", value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChessError {}
+
+impl From<ChessError> for Error {
+    fn from(err: ChessError) -> Error {
+        let kind = match err {
+            ChessError::InvalidUci(_)
+            | ChessError::IllegalSan(_)
+            | ChessError::AmbiguousSan(_)
+            | ChessError::InvalidPgn(_)
+            | ChessError::InvalidTimeControl(_)
+            | ChessError::InvalidChess960Position(_)
+            | ChessError::InvalidFen(_)
+            | ChessError::SideNotToMoveInCheck
+            | ChessError::TooLargeForBinary(_) => ErrorKind::InvalidInput,
+            ChessError::InvalidBinaryData(_) => ErrorKind::InvalidData,
+            _ => ErrorKind::Other,
+        };
+        Error::new(kind, err)
+    }
+}
+
+/// Reason a castling move is illegal, see `ChessGame::castling_illegality_reason`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CastleError {
+    /// The king or that rook has already moved
+    NoRights,
+    /// A square between the king and the rook is occupied
+    PathBlocked,
+    KingInCheck,
+    /// The square the king crosses is attacked
+    PassesThroughCheck,
+    /// The king's destination square is attacked
+    LandsInCheck,
+}
+
+/// Common time controls, named minutes then increment seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeControlPreset {
+    Bullet1_0,
+    Blitz3_2,
+    Blitz5_0,
+    Rapid10_0,
+    Rapid15_10,
+    Classical30_20,
+}
+
+/// How the clock treats each move, amounts in milliseconds
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeControl {
+    /// No time is ever added
+    SuddenDeath,
+    /// The increment is added after every move
+    Fischer(u32),
+    /// After every move, the time used is given back up to the delay
+    Bronstein(u32),
+    /// The clock only starts running once the delay has elapsed
+    SimpleDelay(u32),
+}
+impl TimeControl {
+    /// `Fischer(increment)`, or `SuddenDeath` for no increment
+    pub fn from_increment(increment: u32) -> TimeControl {
+        if increment == 0 {
+            TimeControl::SuddenDeath
+        } else {
+            TimeControl::Fischer(increment)
+        }
+    }
+    /// The Fischer increment, 0 for the other modes
+    pub fn increment(self) -> u32 {
+        match self {
+            TimeControl::Fischer(increment) => increment,
+            _ => 0,
+        }
+    }
+    /// Time a move can take without costing clock time: the increment or the delay
+    pub fn per_move_credit(self) -> u32 {
+        match self {
+            TimeControl::SuddenDeath => 0,
+            TimeControl::Fischer(amount)
+            | TimeControl::Bronstein(amount)
+            | TimeControl::SimpleDelay(amount) => amount,
+        }
+    }
+    /// Used time after a move that took `time_taken`. A Fischer increment is added to the
+    /// clock and can bank time from earlier moves, a delay only covers the move itself.
+    fn settle(self, used: u32, time_taken: u32) -> u32 {
+        match self {
+            TimeControl::SuddenDeath => used.saturating_add(time_taken),
+            TimeControl::Fischer(increment) => {
+                used.saturating_add(time_taken).saturating_sub(increment)
+            }
+            TimeControl::Bronstein(delay) | TimeControl::SimpleDelay(delay) => {
+                used.saturating_add(time_taken.saturating_sub(delay))
+            }
+        }
+    }
+    /// Clock time used so far by a move still being played for `elapsed` milliseconds.
+    /// Bronstein gives the delay back only once the move is made.
+    fn running_time(self, elapsed: u32) -> u32 {
+        match self {
+            TimeControl::SimpleDelay(delay) => elapsed.saturating_sub(delay),
+            _ => elapsed,
+        }
+    }
+}
+
+/// One stage of a tournament time control, times in milliseconds
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeControlStage {
+    /// Moves each player must make in this stage, `None` for the rest of the game
+    pub moves: Option<u32>,
+    /// Time added to the clock when the stage starts
+    pub base_time: u32,
+    pub increment: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameResult {
+    WhiteWins,
+    BlackWins,
+    Draw(DrawReason),
+    Ongoing,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrawReason {
+    Stalemate,
+    Threefold,
+    FiftyMove,
+    InsufficientMaterial,
+    Agreement,
+    TimeoutVsInsufficientMaterial,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chess::*;
+    use pleco::Board;
+
+    #[test]
+    fn single_move_time_used() {
+        let mut game = ChessGameBuilder::new()
+            .with_time_limit(1000 * 60 * 3)
+            .with_increment(10)
+            .build();
+
+        let my_move = Move::new(String::from("e2e4"), 1000);
+        game = game.play_move(my_move).unwrap();
+        game.start_time -= 1000;
+        assert_eq!(game.compute_white_used_time(), 990);
+        assert_eq!(game.compute_black_used_time(), 0);
+    }
+    #[test]
+    fn multiple_move_time_elapsed() {
+        let mut game = ChessGameBuilder::new()
+            .with_time_limit(1000 * 60 * 3)
+            .with_increment(10)
+            .build();
+        let my_move = Move::new(String::from("e2e4"), 1000);
+        game = game.play_move(my_move).unwrap();
+        let my_move = Move::new(String::from("a7a6"), 1000);
+        game = game.play_move(my_move).unwrap();
+        let my_move = Move::new(String::from("g1h3"), 500);
+        game = game.play_move(my_move).unwrap();
+        game.start_time -= 2500;
+        assert_eq!(game.compute_white_used_time(), 1480);
+        assert_eq!(game.compute_black_used_time(), 990);
+    }
+    #[test]
+    fn timeout_against_lone_king_is_draw() {
+        let mut game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("4k3/8/8/8/8/8/8/QK6 w - - 0 1").unwrap())
+            .with_time_limit(1000)
+            .build();
+        game.start_time -= 5000;
+        assert_eq!(
+            game.outcome(),
+            GameResult::Draw(DrawReason::TimeoutVsInsufficientMaterial)
+        );
+    }
+    #[test]
+    fn timeout_against_lone_king_is_loss_when_configured() {
+        let mut game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("4k3/8/8/8/8/8/8/QK6 w - - 0 1").unwrap())
+            .with_time_limit(1000)
+            .with_timeout_insufficient_material_is_draw(false)
+            .build();
+        game.start_time -= 5000;
+        assert_eq!(game.outcome(), GameResult::BlackWins);
+    }
+    #[test]
+    fn eval_history_has_entry_per_position() {
+        let mut game = ChessGameBuilder::new().build();
+        game = game.play_move(Move::new(String::from("e2e4"), 0)).unwrap();
+        game = game.play_move(Move::new(String::from("d7d5"), 0)).unwrap();
+        game = game.play_move(Move::new(String::from("e4d5"), 0)).unwrap();
+        let history = game.eval_history(1);
+        assert_eq!(history.len(), game.moves.len() + 1);
+        assert_eq!(history[0], 0);
+    }
+    #[test]
+    fn first_occurrence_of_repeated_position() {
+        let mut game = ChessGameBuilder::new().build();
+        for uci in ["e2e4", "e7e5", "g1f3", "b8c6"] {
+            game = game.play_move(Move::new(String::from(uci), 0)).unwrap();
+        }
+        assert_eq!(game.first_occurrence_ply(), 0);
+        for uci in ["f3g1", "c6b8"] {
+            game = game.play_move(Move::new(String::from(uci), 0)).unwrap();
+        }
+        assert_eq!(game.first_occurrence_ply(), 2);
+    }
+    #[test]
+    fn builder_seeds_moves_from_movetext() {
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5 2. Nf3 Nc6")
+            .unwrap()
+            .build();
+        let uci_moves: Vec<&str> = game.moves.iter().map(|m| m.uci_move.as_str()).collect();
+        assert_eq!(uci_moves, vec!["e2e4", "e7e5", "g1f3", "b8c6"]);
+        assert!(ChessGameBuilder::new().with_pgn_movetext("1. e5").is_err());
+    }
+    #[test]
+    fn rook_pawn_fortress() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("k7/8/1K6/P7/8/8/8/8 w - - 0 1").unwrap())
+            .build();
+        assert!(game.is_known_fortress());
+    }
+    #[test]
+    fn wrong_bishop_fortress() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("7k/8/8/7P/8/8/4B3/6K1 w - - 0 1").unwrap())
+            .build();
+        assert!(game.is_known_fortress());
+    }
+    #[test]
+    fn right_bishop_is_not_a_fortress() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("7k/8/8/7P/8/8/3B4/6K1 w - - 0 1").unwrap())
+            .build();
+        assert!(!game.is_known_fortress());
+        assert!(!ChessGameBuilder::new().build().is_known_fortress());
+    }
+    #[test]
+    fn divergence_after_shared_opening() {
+        let italian = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5 2. Nf3 Nc6 3. Bc4")
+            .unwrap()
+            .build();
+        let spanish = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5 2. Nf3 Nc6 3. Bb5")
+            .unwrap()
+            .build();
+        let prefix = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5")
+            .unwrap()
+            .build();
+        assert_eq!(divergence_ply(&italian, &spanish), Some(4));
+        assert_eq!(divergence_ply(&italian, &prefix), None);
+    }
+    #[test]
+    fn pawn_push_gains_space() {
+        let mut game = ChessGameBuilder::new().build();
+        assert_eq!(game.space(pleco::Player::White), 0);
+        game = game.play_move(Move::new(String::from("e2e4"), 0)).unwrap();
+        assert!(game.space(pleco::Player::White) > 0);
+        assert_eq!(game.space(pleco::Player::Black), 0);
+    }
+    #[test]
+    fn clock_display_formats_remaining_time() {
+        let mut game = ChessGameBuilder::new()
+            .with_time_limit(1000 * (60 * 60 + 100))
+            .build();
+        game.start_time = 1_000_000;
+        assert_eq!(
+            game.clock_display(1_000_000),
+            (String::from("1:01:40"), String::from("1:01:40"))
+        );
+        game = game
+            .play_move(Move::new(String::from("e2e4"), 5000))
+            .unwrap();
+        assert_eq!(
+            game.clock_display(1_000_000 + 5000 + 2500),
+            (String::from("1:01:35"), String::from("1:01:37"))
+        );
+    }
+    #[test]
+    fn clock_display_clamps_flagged_clock() {
+        let mut game = ChessGameBuilder::new().with_time_limit(1000 * 90).build();
+        game.start_time = 1_000_000;
+        assert_eq!(
+            game.clock_display(1_000_000 + 1000 * 100),
+            (String::from("0:00"), String::from("1:30"))
+        );
+    }
+    #[test]
+    fn underpromotions_exclude_queen() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("8/4P1k1/8/8/8/8/8/4K3 w - - 0 1").unwrap())
+            .build();
+        let moves = game.underpromotion_moves();
+        assert_eq!(moves.len(), 3);
+        assert!(moves.contains(&String::from("e7e8n")));
+        assert!(!moves.contains(&String::from("e7e8q")));
+    }
+    #[test]
+    fn pending_increment_and_budget_follow_stages() {
+        let mut game = ChessGameBuilder::new()
+            .with_time_control_stages(vec![
+                TimeControlStage {
+                    moves: Some(1),
+                    base_time: 60_000,
+                    increment: 0,
+                },
+                TimeControlStage {
+                    moves: None,
+                    base_time: 30_000,
+                    increment: 2000,
+                },
+            ])
+            .build();
+        game.start_time = current_millis();
+        // the first move completes the first stage, adding the second one's base time
+        assert_eq!(game.pending_increment(), 30_000);
+        for uci in ["e2e4", "e7e5"] {
+            game = game.play_move(Move::new(uci.to_string(), 1000)).unwrap();
+        }
+        assert_eq!(game.pending_increment(), 2000);
+        let now = game.start_time + 2000;
+        let clock = game.player_clock(pleco::Player::White, now);
+        assert_eq!(clock, 89_000);
+        assert_eq!(
+            game.time_budget_per_move_at(pleco::Player::White, 2, now),
+            (clock + 2 * 2000) / 2
+        );
+    }
+    #[test]
+    fn projected_time_advantage_follows_stages() {
+        let mut game = ChessGameBuilder::new()
+            .with_time_control_stages(vec![
+                TimeControlStage {
+                    moves: Some(2),
+                    base_time: 60_000,
+                    increment: 0,
+                },
+                TimeControlStage {
+                    moves: None,
+                    base_time: 30_000,
+                    increment: 1000,
+                },
+            ])
+            .build();
+        game.start_time = 1_000_000;
+        for uci in ["e2e4", "e7e5"] {
+            game = game.play_move(Move::new(uci.to_string(), 1000)).unwrap();
+        }
+        let now = 1_000_000 + 2000;
+        let now_advantage = game.projected_time_advantage_at(0, now);
+        // white's second move completes the first stage before black's does
+        assert_eq!(
+            game.projected_time_advantage_at(1, now),
+            now_advantage + 30_000
+        );
+        assert_eq!(game.projected_time_advantage_at(2, now), now_advantage);
+        // white's third move is the first with the second stage's increment
+        assert_eq!(
+            game.projected_time_advantage_at(3, now),
+            now_advantage + 1000
+        );
+        assert_eq!(game.projected_time_advantage_at(4, now), now_advantage);
+    }
+    #[test]
+    fn projected_time_advantage_credits_increment() {
+        let mut game = ChessGameBuilder::new()
+            .with_time_limit(60_000)
+            .with_increment(2000)
+            .build();
+        game.start_time = 1_000_000;
+        game = game
+            .play_move(Move::new(String::from("e2e4"), 10_000))
+            .unwrap();
+        game = game
+            .play_move(Move::new(String::from("e7e5"), 1000))
+            .unwrap();
+        // white: 60s - (10s - 2s) - 1s running = 51s, black: 60s - 0s = 60s
+        let now = 1_000_000 + 11_000 + 1000;
+        assert_eq!(game.projected_time_advantage_at(10, now), -9000);
+        // white, to move, gets the extra increment of an odd number of half-moves
+        assert_eq!(game.projected_time_advantage_at(11, now), -7000);
+        assert_eq!(game.projected_time_advantage_at(0, now), -9000);
+        assert_eq!(
+            game.player_clock(pleco::Player::White, now) + 2000 * 10,
+            71_000
+        );
+    }
+    #[test]
+    fn fen_with_side_not_to_move_in_check_is_rejected() {
+        let err = ChessGameBuilder::new()
+            .with_fen("4k3/8/8/8/8/8/8/4RK2 w - - 0 1")
+            .err()
+            .unwrap();
+        assert_eq!(err, ChessError::SideNotToMoveInCheck);
+        assert!(err.to_string().contains("not to move is in check"));
+        assert!(matches!(
+            ChessGameBuilder::new().with_fen("not a fen"),
+            Err(ChessError::InvalidFen(_))
+        ));
+        assert!(ChessGameBuilder::new()
+            .with_fen("4k3/8/8/8/8/8/8/4RK2 b - - 0 1")
+            .is_ok());
+        let illegal = "4k3/8/8/8/8/8/8/4RK2 w - - 0 1";
+        let json = format!(
+            r#"{{"initial_board":"{}","moves":[],"start_time":0,"time_limit":0,"increment":0}}"#,
+            illegal
+        );
+        let err = serde_json::from_str::<ChessGame>(&json).err().unwrap();
+        assert!(
+            err.to_string().contains("not to move is in check"),
+            "{}",
+            err
+        );
+        let json = format!(r#"["{}",[],0,0,0]"#, illegal);
+        assert!(serde_json::from_str::<ChessGame>(&json).is_err());
+        let pgn = format!("[FEN \"{}\"]\n\n*\n", illegal);
+        assert_eq!(
+            ChessGame::from_pgn(&pgn).err(),
+            Some(ChessError::SideNotToMoveInCheck)
+        );
+    }
+    #[test]
+    fn opening_name_from_longest_matching_line() {
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 c5 2. a3")
+            .unwrap()
+            .build();
+        assert_eq!(game.opening_name(), Some(String::from("Sicilian Defense")));
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 4. Ba4")
+            .unwrap()
+            .build();
+        assert_eq!(
+            game.opening_name(),
+            Some(String::from("Ruy Lopez: Morphy Defense"))
+        );
+        assert_eq!(ChessGameBuilder::new().build().opening_name(), None);
+        assert_eq!(opening_lines().len(), OPENINGS_TSV.lines().count() - 1);
+    }
+    #[test]
+    fn draw_claim_lapses_after_window() {
+        let shuffle = "Nf3 Nf6 Ng1 Ng8 Nf3 Nf6 Ng1 Ng8";
+        let game = ChessGameBuilder::new()
+            .with_draw_claim_window(1)
+            .with_pgn_movetext(shuffle)
+            .unwrap()
+            .build();
+        assert_eq!(game.can_claim_draw(), Some(DrawReason::Threefold));
+        let game = game.play_move(Move::new(String::from("e2e4"), 0)).unwrap();
+        assert_eq!(game.can_claim_draw(), Some(DrawReason::Threefold));
+        let game = game.play_move(Move::new(String::from("e7e5"), 0)).unwrap();
+        assert_eq!(game.can_claim_draw(), None);
+
+        let game = ChessGameBuilder::new()
+            .with_draw_claim_window(1)
+            .with_pgn_movetext(&format!("{} e4 e5 {}", shuffle, shuffle))
+            .unwrap()
+            .build();
+        assert_eq!(game.can_claim_draw(), Some(DrawReason::Threefold));
+    }
+    #[test]
+    fn draw_claim_without_window_needs_position_on_board() {
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("Nf3 Nf6 Ng1 Ng8 Nf3 Nf6 Ng1 Ng8")
+            .unwrap()
+            .build();
+        assert_eq!(game.can_claim_draw(), Some(DrawReason::Threefold));
+        assert_eq!(game.result(), GameResult::Draw(DrawReason::Threefold));
+        let game = game.play_move(Move::new(String::from("e2e4"), 0)).unwrap();
+        assert_eq!(game.can_claim_draw(), None);
+        // playing on past an unclaimed repetition leaves the game going
+        assert!(game.is_threefold_repetition());
+        assert_eq!(game.result(), GameResult::Ongoing);
+        assert_eq!(game.score_for(pleco::Player::White), None);
+        assert!(game.to_pgn().contains("[Result \"*\"]"));
+    }
+    #[test]
+    fn kings_in_direct_opposition_are_two_apart() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("8/8/4k3/8/4K3/8/8/8 w - - 0 1").unwrap())
+            .build();
+        assert_eq!(game.king_distance(), 2);
+    }
+    #[test]
+    fn direct_opposition_belongs_to_side_not_to_move() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("8/8/4k3/8/4K3/4P3/8/8 b - - 0 1").unwrap())
+            .build();
+        assert!(game.has_opposition(pleco::Player::White));
+        assert!(!game.has_opposition(pleco::Player::Black));
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("8/8/3k4/8/4K3/4P3/8/8 b - - 0 1").unwrap())
+            .build();
+        assert!(!game.has_opposition(pleco::Player::White));
+    }
+    #[test]
+    fn start_position_has_twenty_children() {
+        let game = ChessGameBuilder::new().build();
+        let children = game.children();
+        assert_eq!(children.len(), 20);
+        assert!(children.iter().all(|child| child.moves.len() == 1));
+        assert!(game.moves.is_empty());
+    }
+    #[test]
+    fn flagged_player_after_white_runs_out() {
+        let mut game = ChessGameBuilder::new().with_time_limit(1000 * 60).build();
+        assert_eq!(game.flagged_player(), None);
+        game.start_time -= 1000 * 61;
+        assert_eq!(game.flagged_player(), Some(pleco::Player::White));
+    }
+    #[test]
+    fn remaining_time_after_each_move() {
+        let mut game = ChessGameBuilder::new()
+            .with_time_limit(60_000)
+            .with_increment(1000)
+            .build();
+        game = game
+            .play_move(Move::new(String::from("e2e4"), 3000))
+            .unwrap();
+        game = game
+            .play_move(Move::new(String::from("e7e5"), 500))
+            .unwrap();
+        game = game
+            .play_move(Move::new(String::from("g1f3"), 4000))
+            .unwrap();
+        assert_eq!(
+            game.remaining_time_annotations(),
+            vec![
+                (pleco::Player::White, 58_000),
+                (pleco::Player::Black, 60_000),
+                (pleco::Player::White, 55_000),
+            ]
+        );
+    }
+    #[test]
+    fn capture_down_to_bare_kings_auto_draws() {
+        let fen = "7k/8/8/8/3q4/4K3/8/8 w - - 0 1";
+        let mut game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen(fen).unwrap())
+            .build();
+        assert_eq!(game.outcome(), GameResult::Ongoing);
+        game = game.play_move(Move::new(String::from("e3d4"), 0)).unwrap();
+        assert_eq!(
+            game.outcome(),
+            GameResult::Draw(DrawReason::InsufficientMaterial)
+        );
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen(fen).unwrap())
+            .with_auto_draw_on_insufficient_material(false)
+            .build()
+            .play_move(Move::new(String::from("e3d4"), 0))
+            .unwrap();
+        assert_eq!(game.outcome(), GameResult::Ongoing);
+    }
+    #[test]
+    fn last_move_san_marks_check() {
+        let game = ChessGameBuilder::new().build();
+        assert_eq!(game.last_move_san(), None);
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 f6 2. Qh5+")
+            .unwrap()
+            .build();
+        assert_eq!(game.last_move_san(), Some(String::from("Qh5+")));
+    }
+    #[test]
+    fn extra_queen_is_likely_decided() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("4k3/8/8/8/8/8/8/3QK3 w - - 0 1").unwrap())
+            .build();
+        assert_eq!(game.is_likely_decided(1, 500), Some(pleco::Player::White));
+        assert_eq!(
+            ChessGameBuilder::new().build().is_likely_decided(1, 500),
+            None
+        );
+    }
+    #[test]
+    fn estimated_size_grows_with_moves() {
+        let empty = ChessGameBuilder::new().build();
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5 2. Nf3 Nc6 3. Bb5 a6")
+            .unwrap()
+            .build();
+        let minimum = std::mem::size_of::<ChessGame>() + 6 * (std::mem::size_of::<Move>() + 4);
+        assert!(game.estimated_size_bytes() >= minimum);
+        assert!(game.estimated_size_bytes() > empty.estimated_size_bytes());
+    }
+    #[test]
+    fn sync_sends_only_moves_after_known_position() {
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5 2. Nf3 Nc6 3. Bb5 a6")
+            .unwrap()
+            .build();
+        let known_hash = game.position_hashes()[3];
+        match game.sync_against(known_hash) {
+            SyncResult::Delta(moves) => {
+                let uci_moves: Vec<&str> = moves.iter().map(|m| m.uci_move.as_str()).collect();
+                assert_eq!(uci_moves, vec!["b8c6", "f1b5", "a7a6"]);
+            }
+            SyncResult::Snapshot(_) => panic!("expected a delta"),
+        }
+        assert!(matches!(game.sync_against(0), SyncResult::Snapshot(_)));
+        assert!(matches!(
+            game.sync_against(game.position_hash()),
+            SyncResult::Delta(moves) if moves.is_empty()
+        ));
+    }
+    #[test]
+    fn first_move_time_is_attributed_from_start() {
+        let mut game = ChessGameBuilder::new()
+            .with_time_limit(60_000)
+            .with_auto_first_move_time(true)
+            .build();
+        game.start_time = 1_000_000;
+        game = game
+            .play_move_at(Move::new(String::from("e2e4"), 0), 1_000_000 + 4200)
+            .unwrap();
+        assert_eq!(game.moves[0].time_taken, 4200);
+        game = game
+            .play_move_at(Move::new(String::from("e7e5"), 0), 1_000_000 + 9000)
+            .unwrap();
+        assert_eq!(game.moves[1].time_taken, 0);
+    }
+    #[test]
+    fn pawn_on_seventh_rank_requires_promotion() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("4k3/P7/8/8/8/8/4p3/4K3 w - - 0 1").unwrap())
+            .build();
+        assert!(game.requires_promotion("a7", "a8").unwrap());
+        assert!(game.requires_promotion("e2", "e1").unwrap());
+        assert!(!ChessGameBuilder::new()
+            .build()
+            .requires_promotion("e2", "e4")
+            .unwrap());
+        assert!(game.requires_promotion("e1", "e2").is_err());
+        assert!(game.requires_promotion("a7", "a9").is_err());
+    }
+    #[test]
+    fn start_position_tapered_eval_is_balanced() {
+        let game = ChessGameBuilder::new().build();
+        assert!(game.tapered_eval().abs() <= 10);
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("4k3/8/8/8/8/8/8/3QK3 w - - 0 1").unwrap())
+            .build();
+        assert!(game.tapered_eval() > 800);
+    }
+    #[test]
+    fn knight_shuffle_is_one_move_from_repetition() {
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. Nf3 Nf6 2. Ng1 Ng8 3. Nf3 Nf6 4. Ng1")
+            .unwrap()
+            .build();
+        assert_eq!(game.repetition_draw_in_one(), vec![String::from("f6g8")]);
+        assert!(ChessGameBuilder::new()
+            .build()
+            .repetition_draw_in_one()
+            .is_empty());
+    }
+    #[test]
+    fn study_json_has_root_and_node_per_move() {
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5 2. Nf3")
+            .unwrap()
+            .build();
+        let nodes = game.to_study_json();
+        let nodes = nodes.as_array().unwrap();
+        assert_eq!(nodes.len(), 4);
+        assert_eq!(nodes[0]["fen"], Board::start_pos().fen());
+        assert_eq!(nodes[0]["san"], serde_json::Value::Null);
+        assert_eq!(nodes[3]["san"], "Nf3");
+        assert_eq!(nodes[3]["uci"], "g1f3");
+    }
+    #[test]
+    fn back_rank_mate_is_unique_best() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1").unwrap())
+            .build();
+        assert!(game.is_unique_best("a1a8", 1, 200).unwrap());
+        assert!(!game.is_unique_best("g1f1", 1, 200).unwrap());
+        assert!(game.is_unique_best("a1h8", 1, 200).is_err());
+    }
+    #[test]
+    fn blitz_preset_sets_limit_and_increment() {
+        let game = ChessGameBuilder::new()
+            .with_preset(TimeControlPreset::Blitz3_2)
+            .build();
+        assert_eq!(game.time_limit, 180_000);
+        assert_eq!(game.time_control, TimeControl::Fischer(2000));
+    }
+    #[test]
+    fn score_for_white_after_win_loss_and_draw() {
+        let win = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5 2. Bc4 Nc6 3. Qh5 Nf6 4. Qxf7#")
+            .unwrap()
+            .build();
+        assert_eq!(win.score_for(pleco::Player::White), Some(1.0));
+        let loss = ChessGameBuilder::new()
+            .with_pgn_movetext("1. f3 e5 2. g4 Qh4#")
+            .unwrap()
+            .build();
+        assert_eq!(loss.score_for(pleco::Player::White), Some(0.0));
+        assert_eq!(loss.score_for(pleco::Player::Black), Some(1.0));
+        let draw = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("4k3/8/8/8/8/8/8/4K3 w - - 0 1").unwrap())
+            .build();
+        assert_eq!(draw.score_for(pleco::Player::White), Some(0.5));
+        let ongoing = ChessGameBuilder::new().build();
+        assert_eq!(ongoing.score_for(pleco::Player::White), None);
+    }
+    #[test]
+    fn castling_illegality_reasons() {
+        let reason = |fen: &str, uci: &str| {
+            ChessGameBuilder::new()
+                .with_initial_board(Board::from_fen(fen).unwrap())
+                .build()
+                .castling_illegality_reason(uci)
+        };
+        assert_eq!(
+            reason("r3k2r/8/8/8/8/8/8/R3K2R w - - 0 1", "e1g1"),
+            Some(CastleError::NoRights)
+        );
+        assert_eq!(
+            reason("r3k2r/8/8/8/8/8/8/RN2K2R w KQkq - 0 1", "e1c1"),
+            Some(CastleError::PathBlocked)
+        );
+        assert_eq!(
+            reason("4r1k1/8/8/8/8/8/8/R3K2R w KQ - 0 1", "e1g1"),
+            Some(CastleError::KingInCheck)
+        );
+        assert_eq!(
+            reason("5rk1/8/8/8/8/8/8/R3K2R w KQ - 0 1", "e1g1"),
+            Some(CastleError::PassesThroughCheck)
+        );
+        assert_eq!(
+            reason("6rk/8/8/8/8/8/8/R3K2R w KQ - 0 1", "e1g1"),
+            Some(CastleError::LandsInCheck)
+        );
+        assert_eq!(reason("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "e1g1"), None);
+        assert_eq!(reason("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "e1e2"), None);
+    }
+    #[test]
+    fn hanging_queen_is_more_complex_than_forced_recapture() {
+        let forced = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("7k/8/8/8/8/8/6q1/7K w - - 0 1").unwrap())
+            .build();
+        let tactical = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("4k3/8/8/3q4/8/8/3R4/4K3 w - - 0 1").unwrap())
+            .build();
+        assert_eq!(forced.complexity(), 0.0);
+        assert!(tactical.complexity() > forced.complexity());
+    }
+    #[test]
+    fn infer_line_finds_single_capture() {
+        let start = "4k3/8/8/3q4/8/8/3R4/4K3 w - - 0 1";
+        let end = "4k3/8/8/3R4/8/8/8/4K3 b - - 0 1";
+        assert_eq!(
+            infer_line(start, end, 3, 1),
+            Some(vec![String::from("d2d5")])
+        );
+        assert_eq!(infer_line(start, start, 3, 1), Some(Vec::new()));
+        assert_eq!(infer_line(start, end, 0, 1), None);
+        // white has no pawn to promote into a second rook, so nothing is searched
+        let extra_rook = "4k3/8/8/3q4/8/8/3R4/R3K3 b - - 0 1";
+        assert_eq!(infer_line(start, extra_rook, 40, 40), None);
+        let board = Board::from_fen(start).unwrap();
+        assert!(!material_reachable(
+            &board,
+            &Board::from_fen(extra_rook).unwrap()
+        ));
+        assert!(material_reachable(&board, &Board::from_fen(end).unwrap()));
+    }
+    #[test]
+    fn centralized_king_is_more_active() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("7k/8/8/8/3K4/8/8/8 w - - 0 1").unwrap())
+            .build();
+        assert!(
+            game.king_activity(pleco::Player::White) > game.king_activity(pleco::Player::Black)
+        );
+    }
+    #[test]
+    fn pending_increment_mid_game() {
+        let game = ChessGameBuilder::new()
+            .with_time_limit(180_000)
+            .with_increment(2000)
+            .with_pgn_movetext("1. e4 e5")
+            .unwrap()
+            .build();
+        assert_eq!(game.pending_increment(), 2000);
+        let untimed = ChessGameBuilder::new().with_increment(2000).build();
+        assert_eq!(untimed.pending_increment(), 0);
+    }
+    #[test]
+    fn pawn_on_seventh_rank_can_promote() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("7k/P7/8/8/8/8/8/K7 w - - 0 1").unwrap())
+            .build();
+        assert!(game.promotion_available(pleco::Player::White));
+        assert!(!game.promotion_available(pleco::Player::Black));
+        let blocked = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("n6k/P7/8/8/8/8/8/K7 w - - 0 1").unwrap())
+            .build();
+        assert!(!blocked.promotion_available(pleco::Player::White));
+    }
+    #[test]
+    fn time_budget_splits_remaining_time() {
+        let mut game = ChessGameBuilder::new()
+            .with_time_limit(60_000)
+            .with_increment(1000)
+            .build();
+        game.start_time = 1_000_000;
+        game = game
+            .play_move(Move::new(String::from("e2e4"), 11_000))
+            .unwrap();
+        let now = 1_000_000 + 11_000;
+        // white: 60s - (11s - 1s) = 50s, plus 10 increments of 1s over 10 moves
+        assert_eq!(
+            game.time_budget_per_move_at(pleco::Player::White, 10, now),
+            6000
+        );
+        assert_eq!(
+            game.time_budget_per_move_at(pleco::Player::White, 0, now),
+            51_000
+        );
+    }
+    #[test]
+    fn annotated_moves_combine_san_uci_and_time() {
+        let game = ChessGameBuilder::new()
+            .build()
+            .play_move(Move::new(String::from("e2e4"), 1500))
+            .unwrap()
+            .play_move(Move::new(String::from("g8f6"), 800))
+            .unwrap();
+        let annotated = game.annotated_moves();
+        assert_eq!(annotated.len(), 2);
+        assert_eq!(
+            annotated[0],
+            (String::from("e4"), String::from("e2e4"), 1500)
+        );
+        assert_eq!(annotated[1].0, "Nf6");
+    }
+    #[test]
+    fn scholars_mate_is_a_miniature() {
+        let mate = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5 2. Bc4 Nc6 3. Qh5 Nf6 4. Qxf7#")
+            .unwrap()
+            .build();
+        assert!(mate.is_miniature());
+        let long_draw = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("4k3/8/8/8/8/8/3p4/4K3 w - - 0 60").unwrap())
+            .with_pgn_movetext("60. Kxd2")
+            .unwrap()
+            .build();
+        assert_eq!(long_draw.full_move_number(), 60);
+        assert!(!long_draw.is_miniature());
+        let opening = ChessGameBuilder::new()
+            .with_time_limit(60_000)
+            .build()
+            .play_move(Move::new(String::from("e2e4"), 70_000))
+            .unwrap();
+        assert_eq!(opening.outcome(), GameResult::BlackWins);
+        assert!(!opening.is_miniature());
+        // the flag already ended the game, so a later resignation changes nothing
+        assert!(!opening.resign(pleco::Player::Black).is_miniature());
+        let resigned = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5")
+            .unwrap()
+            .build()
+            .resign(pleco::Player::Black);
+        assert!(resigned.is_miniature());
+    }
+    #[test]
+    fn queen_promotion_gains_eight_hundred() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("7k/P7/8/8/8/8/8/K7 w - - 0 1").unwrap())
+            .build()
+            .play_move(Move::new(String::from("a7a8q"), 0))
+            .unwrap();
+        assert_eq!(game.promoted_material(pleco::Player::White), 800);
+        assert_eq!(game.promoted_material(pleco::Player::Black), 0);
+    }
+    #[test]
+    fn hanging_queen_is_the_critical_moment() {
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5 2. Qg4 d6 3. d3 Bxg4")
+            .unwrap()
+            .build();
+        // 3. d3 lets black take the queen
+        assert_eq!(game.critical_moment(1), Some(4));
+        assert_eq!(game.moves()[4].uci_move(), "d2d3");
+        assert_eq!(ChessGameBuilder::new().build().critical_moment(1), None);
+    }
+    #[test]
+    fn share_token_round_trip() {
+        let game = ChessGameBuilder::new()
+            .with_time_limit(300_000)
+            .with_increment(3000)
+            .with_draw_claim_window(2)
+            .build()
+            .play_move(Move::new(String::from("e2e4"), 1200))
+            .unwrap()
+            .play_move(Move::new(String::from("d7d5"), 3400))
+            .unwrap();
+        let token = game.to_share_token().unwrap();
+        assert!(token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        let decoded = ChessGame::from_share_token(&token).unwrap();
+        assert_eq!(
+            serde_json::to_string(&decoded).unwrap(),
+            serde_json::to_string(&game).unwrap()
+        );
+        let promotion = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("7k/P7/8/8/8/8/8/K7 w - - 0 1").unwrap())
+            .build()
+            .play_move(Move::new(String::from("a7a8n"), 0))
+            .unwrap();
+        let decoded = ChessGame::from_share_token(&promotion.to_share_token().unwrap()).unwrap();
+        assert_eq!(decoded.moves[0].uci_move, "a7a8n");
+        assert_eq!(decoded.initial_board.fen(), promotion.initial_board.fen());
+        assert!(ChessGame::from_share_token("not a token!").is_err());
+    }
+    #[test]
+    fn queens_gone_after_trade() {
+        assert_eq!(
+            ChessGameBuilder::new().build().queens_on_board(),
+            (true, true)
+        );
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. d4 e5 2. dxe5 d6 3. exd6 Qxd6 4. Qxd6 Bxd6")
+            .unwrap()
+            .build();
+        assert_eq!(game.queens_on_board(), (false, false));
+    }
+    #[test]
+    fn hanging_knight_can_retreat() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("4k3/8/8/3r4/8/3N4/8/4K3 w - - 0 1").unwrap())
+            .build();
+        let moves = game.defensive_moves("d3").unwrap();
+        assert!(moves.contains(&String::from("d3b2")));
+        assert!(moves.contains(&String::from("e1e2")));
+        assert!(!moves.contains(&String::from("e1f1")));
+        assert!(game.defensive_moves("d5").is_err());
+    }
+    #[test]
+    fn perpetual_check_is_told_apart_from_quiet_repetition() {
+        let mut perpetual = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("8/6pk/7p/5Q2/8/8/8/K7 b - - 0 1").unwrap())
+            .build();
+        for uci_move in ["h7g8", "f5c8", "g8h7", "c8f5"] {
+            perpetual = perpetual
+                .play_move(Move::new(String::from(uci_move), 0))
+                .unwrap();
+        }
+        assert_eq!(
+            perpetual.drawing_mechanism(),
+            Some(DrawMechanism::PerpetualCheck)
+        );
+        let quiet = ChessGameBuilder::new()
+            .with_pgn_movetext("1. Nf3 Nf6 2. Ng1 Ng8")
+            .unwrap()
+            .build();
+        assert_eq!(
+            quiet.drawing_mechanism(),
+            Some(DrawMechanism::PositionalRepetition)
+        );
+        assert_eq!(ChessGameBuilder::new().build().drawing_mechanism(), None);
+    }
+    #[test]
+    fn player_clock_matches_clock_display() {
+        let mut game = ChessGameBuilder::new()
+            .with_time_limit(60_000)
+            .with_increment(1000)
+            .build();
+        game.start_time = 1_000_000;
+        game = game
+            .play_move(Move::new(String::from("e2e4"), 5000))
+            .unwrap();
+        let now = 1_000_000 + 5000 + 2500;
+        assert_eq!(game.player_clock(pleco::Player::White, now), 56_000);
+        assert_eq!(game.player_clock(pleco::Player::Black, now), 57_500);
+        let (white, black) = game.clock_display(now);
+        assert_eq!(
+            white,
+            format_clock(game.player_clock(pleco::Player::White, now))
+        );
+        assert_eq!(
+            black,
+            format_clock(game.player_clock(pleco::Player::Black, now))
+        );
+    }
+    #[test]
+    fn queen_move_that_stalemates_is_flagged() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("k7/8/1K6/8/8/8/8/2Q5 w - - 0 1").unwrap())
+            .build();
+        assert!(!game.avoids_stalemate("c1c7").unwrap());
+        assert!(game.avoids_stalemate("c1c8").unwrap());
+        assert!(game.avoids_stalemate("c1c2").unwrap());
+        assert!(game.avoids_stalemate("c1h7").is_err());
+    }
+    #[test]
+    fn start_position_has_thirty_two_pieces() {
+        assert_eq!(ChessGameBuilder::new().build().piece_count(), 32);
+    }
+    #[test]
+    fn capture_enters_six_piece_tablebase() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("4k3/pp6/8/3q4/8/8/3R1P2/4K3 w - - 0 1").unwrap())
+            .with_pgn_movetext("1. Rxd5 Ke7 2. f4")
+            .unwrap()
+            .build();
+        assert_eq!(game.entered_tablebase_at(6), Some(1));
+        assert_eq!(game.entered_tablebase_at(7), Some(0));
+        assert_eq!(game.entered_tablebase_at(5), None);
+    }
+    #[test]
+    fn white_and_black_pure_times_differ() {
+        let game = ChessGameBuilder::new()
+            .build()
+            .play_move(Move::new(String::from("e2e4"), 1000))
+            .unwrap()
+            .play_move(Move::new(String::from("e7e5"), 2500))
+            .unwrap()
+            .play_move(Move::new(String::from("g1f3"), 500))
+            .unwrap();
+        assert_eq!(game.compute_white_moves_pure_time(), 1500);
+        assert_eq!(game.compute_black_moves_pure_time(), 2500);
+        assert_ne!(
+            game.compute_white_moves_pure_time(),
+            game.compute_black_moves_pure_time()
+        );
+    }
+    #[test]
+    fn e5_after_e4_is_a_book_move() {
+        let game = ChessGameBuilder::new()
+            .build()
+            .play_move(Move::new(String::from("e2e4"), 0))
+            .unwrap();
+        assert!(game.is_book_move("e7e5"));
+        assert!(!game.is_book_move("g8h6"));
+    }
+    #[test]
+    fn undo_move_restores_previous_board() {
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5")
+            .unwrap()
+            .build();
+        let before = game.compute_current_board().fen();
+        let game = game
+            .play_move(Move::new(String::from("g1f3"), 0))
+            .unwrap()
+            .undo_move()
+            .unwrap();
+        assert_eq!(game.compute_current_board().fen(), before);
+    }
+    #[test]
+    fn undoing_every_move_restores_initial_board() {
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5 2. Nf3")
+            .unwrap()
+            .build();
+        let initial_fen = game.initial_board.fen();
+        let game = game
+            .undo_move()
+            .unwrap()
+            .undo_move()
+            .unwrap()
+            .undo_move()
+            .unwrap();
+        assert_eq!(game.compute_current_board().fen(), initial_fen);
+        assert!(game.undo_move().is_err());
+    }
+    #[test]
+    fn time_handicap_ratio_for_odds_game() {
+        let game = ChessGameBuilder::new()
+            .with_time_limit(300_000)
+            .with_black_time_limit(150_000)
+            .build();
+        assert_eq!(game.time_handicap_ratio(), 2.0);
+        assert_eq!(game.time_limit_for(pleco::Player::Black), 150_000);
+        assert_eq!(ChessGameBuilder::new().build().time_handicap_ratio(), 1.0);
+        let decoded = ChessGame::from_share_token(&game.to_share_token().unwrap()).unwrap();
+        assert_eq!(decoded.time_limit_for(pleco::Player::Black), 150_000);
+    }
+    #[test]
+    fn start_time_in_the_future_does_not_underflow() {
+        let mut game = ChessGameBuilder::new().with_time_limit(60_000).build();
+        game.start_time = 1_000_000;
+        assert_eq!(game.compute_move_time_at(999_000), 0);
+        game.start_time = u64::MAX;
+        assert_eq!(game.compute_current_move_time(), 0);
+        assert_eq!(game.compute_white_used_time(), 0);
+        assert!(!game.is_white_time_over());
+    }
+    #[test]
+    fn tampered_game_fails_invariants() {
+        let mut game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5")
+            .unwrap()
+            .build();
+        assert_eq!(game.check_invariants(), Ok(()));
+        game.moves.push(Move::new(String::from("d2d5"), 0));
+        assert_eq!(
+            game.check_invariants(),
+            Err(String::from("move 3 (d2d5) is illegal"))
+        );
+        let game = ChessGameBuilder::new()
+            .with_time_limit(1000)
+            .with_increment(2000)
+            .build();
+        assert_eq!(
+            game.check_invariants(),
+            Err(String::from(
+                "increment 2000 exceeds the time limit 1000 of White"
+            ))
+        );
+        let staged = ChessGameBuilder::new()
+            .with_time_control_stages(vec![
+                TimeControlStage {
+                    moves: Some(40),
+                    base_time: 60_000,
+                    increment: 0,
+                },
+                TimeControlStage {
+                    moves: None,
+                    base_time: 30_000,
+                    increment: 90_000,
+                },
+            ])
+            .build();
+        assert_eq!(
+            staged.check_invariants(),
+            Err(String::from(
+                "increment 90000 exceeds the time limit 60000 of White"
+            ))
+        );
+        let mut mated = ChessGameBuilder::new()
+            .with_pgn_movetext("1. f3 e5 2. g4 Qh4#")
+            .unwrap()
+            .build();
+        mated.result_override = Some(GameResult::WhiteWins);
+        assert_eq!(
+            mated.check_invariants(),
+            Err(String::from(
+                "result WhiteWins contradicts BlackWins on the board"
+            ))
+        );
+        mated.result_override = Some(GameResult::Ongoing);
+        assert_eq!(
+            mated.check_invariants(),
+            Err(String::from(
+                "result Ongoing is not a resignation or an agreed draw"
+            ))
+        );
+        let resigned = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5")
+            .unwrap()
+            .build()
+            .resign(pleco::Player::White);
+        assert_eq!(resigned.check_invariants(), Ok(()));
+    }
+    #[test]
+    fn moves_getter_returns_played_moves() {
+        let game = ChessGameBuilder::new()
+            .build()
+            .play_move(Move::new(String::from("e2e4"), 1200))
+            .unwrap()
+            .play_move(Move::new(String::from("c7c5"), 800))
+            .unwrap()
+            .play_move(Move::new(String::from("g1f3"), 3000))
+            .unwrap();
+        let moves = game.moves();
+        assert_eq!(moves.len(), 3);
+        assert_eq!(moves[0].uci_move(), "e2e4");
+        assert_eq!(moves[1].uci_move(), "c7c5");
+        assert_eq!(moves[2].uci_move(), "g1f3");
+        assert_eq!(moves[2].time_taken(), 3000);
+    }
+    #[test]
+    fn analysis_iter_yields_one_item_per_move() {
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5 2. Qg4 d6 3. d3 Bxg4")
+            .unwrap()
+            .build();
+        let analysis: Vec<(usize, i32, String)> = game.analysis_iter(1).collect();
+        assert_eq!(analysis.len(), game.moves.len());
+        assert_eq!(analysis[0].0, 0);
+        assert!(analysis[0].1.abs() < 200);
+        // black to move can win the queen on g4
+        assert_eq!(analysis[5].2, "c8g4");
+        assert!(analysis[5].1 < -500);
+    }
+    #[test]
+    fn fen_after_e4() {
+        let game = ChessGameBuilder::new()
+            .build()
+            .play_move(Move::new(String::from("e2e4"), 0))
+            .unwrap();
+        let mut board = Board::start_pos();
+        board.apply_uci_move("e2e4");
+        let after_e4 = board.fen();
+        assert!(after_e4.starts_with("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq"));
+        assert_eq!(game.current_fen(), after_e4);
+        assert_eq!(game.fen_at_turn(0), Board::start_pos().fen());
+        assert_eq!(game.fen_at_turn(1), after_e4);
+        assert_eq!(game.fen_at_turn(5), after_e4);
+    }
+    #[test]
+    fn knight_shuffle_reaches_threefold() {
+        let twice = ChessGameBuilder::new()
+            .with_pgn_movetext("1. Nf3 Nf6 2. Ng1 Ng8")
+            .unwrap()
+            .build();
+        assert!(!twice.is_threefold_repetition());
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. Nf3 Nf6 2. Ng1 Ng8 3. Nf3 Nf6 4. Ng1 Ng8")
+            .unwrap()
+            .build();
+        assert!(game.is_threefold_repetition());
+    }
+    #[test]
+    fn hundred_knight_moves_trigger_fifty_move_draw() {
+        let mut game = ChessGameBuilder::new().build();
+        for _ in 0..25 {
+            for uci_move in ["g1f3", "g8f6", "f3g1", "f6g8"] {
+                assert!(!game.is_fifty_move_draw());
+                game = game
+                    .play_move(Move::new(String::from(uci_move), 0))
+                    .unwrap();
+            }
+        }
+        assert!(game.is_fifty_move_draw());
+        let from_fen = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("4k3/8/8/8/8/8/4P3/R3K3 w - - 99 80").unwrap())
+            .build();
+        assert!(!from_fen.is_fifty_move_draw());
+        assert!(from_fen
+            .play_move(Move::new(String::from("a1a2"), 0))
+            .unwrap()
+            .is_fifty_move_draw());
+    }
+    #[test]
+    fn insufficient_material_positions() {
+        let insufficient = |fen: &str| {
+            ChessGameBuilder::new()
+                .with_initial_board(Board::from_fen(fen).unwrap())
+                .build()
+                .is_insufficient_material()
+        };
+        assert!(insufficient("4k3/8/8/8/8/8/8/4K3 w - - 0 1"));
+        assert!(insufficient("4k3/8/8/8/8/8/8/2B1K3 w - - 0 1"));
+        assert!(insufficient("4k3/8/8/8/8/8/8/1N2K3 w - - 0 1"));
+        // c1 and f8 are both dark squares
+        assert!(insufficient("4kb2/8/8/8/8/8/8/2B1K3 w - - 0 1"));
+        assert!(!insufficient("2b1k3/8/8/8/8/8/8/2B1K3 w - - 0 1"));
+        assert!(!insufficient("4k3/8/8/8/8/8/8/1N2K1N1 w - - 0 1"));
+        assert!(!ChessGameBuilder::new().build().is_insufficient_material());
+    }
+    #[test]
+    fn fools_mate_result() {
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. f3 e5 2. g4 Qh4#")
+            .unwrap()
+            .build();
+        assert_eq!(game.result(), GameResult::BlackWins);
+    }
+    #[test]
+    fn stalemate_result() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("k7/2Q5/1K6/8/8/8/8/8 b - - 0 1").unwrap())
+            .build();
+        assert_eq!(game.result(), GameResult::Draw(DrawReason::Stalemate));
+        assert_eq!(
+            ChessGameBuilder::new().build().result(),
+            GameResult::Ongoing
+        );
+    }
+    #[test]
+    fn queen_stalemate_is_not_checkmate() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1").unwrap())
+            .build();
+        assert!(game.is_stalemate());
+        assert!(!game.is_checkmate());
+        assert!(!ChessGameBuilder::new().build().is_stalemate());
+    }
+    #[test]
+    fn play_and_undo_return_typed_errors() {
+        let game = ChessGameBuilder::new().build();
+        assert_eq!(
+            game.clone()
+                .play_move(Move::new(String::from("e2e5"), 0))
+                .err(),
+            Some(ChessError::IllegalMove {
+                uci: String::from("e2e5")
+            })
+        );
+        assert_eq!(
+            game.clone()
+                .play_move(Move::new(String::from("e2-e4"), 0))
+                .err(),
+            Some(ChessError::InvalidUci(String::from("e2-e4")))
+        );
+        assert_eq!(game.undo_move().err(), Some(ChessError::NoMovesToUndo));
+        let mated = ChessGameBuilder::new()
+            .with_pgn_movetext("1. f3 e5 2. g4 Qh4#")
+            .unwrap()
+            .build();
+        assert_eq!(
+            mated.play_move(Move::new(String::from("e1f2"), 0)).err(),
+            Some(ChessError::GameOver)
+        );
+    }
+    #[test]
+    fn no_moves_after_fools_mate() {
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. f3 e5 2. g4 Qh4#")
+            .unwrap()
+            .build();
+        assert_eq!(
+            game.play_move(Move::new(String::from("a2a3"), 0)).err(),
+            Some(ChessError::GameOver)
+        );
+    }
+    #[test]
+    fn uci_grammar_is_validated() {
+        assert!(Move::new(String::from("e2e"), 0).validate_uci().is_err());
+        assert!(Move::new(String::from("e2e9"), 0).validate_uci().is_err());
+        assert!(Move::new(String::from("i2e4"), 0).validate_uci().is_err());
+        assert!(Move::new(String::from("e7e8k"), 0).validate_uci().is_err());
+        assert_eq!(Move::new(String::from("e7e8q"), 0).validate_uci(), Ok(()));
+        let game = ChessGameBuilder::new().build();
+        assert_eq!(
+            game.play_move(Move::new(String::from("xyz"), 0)).err(),
+            Some(ChessError::InvalidUci(String::from("xyz")))
+        );
+    }
+    #[test]
+    fn promotion_round_trips_through_json() {
+        let game = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("8/4P3/8/8/8/8/k7/4K3 w - - 0 1").unwrap())
+            .build()
+            .play_move(Move::new(String::from("e7e8Q"), 0))
+            .unwrap();
+        assert_eq!(game.moves[0].uci_move, "e7e8q");
+        let json = serde_json::to_string(&game).unwrap();
+        assert!(json.contains("e7e8q"));
+        let game: ChessGame = serde_json::from_str(&json).unwrap();
+        let board = game.compute_current_board();
+        let e8 = parse_square("e8").unwrap();
+        assert_eq!(board.piece_at_sq(e8).type_of(), pleco::PieceType::Q);
+        assert_ne!(
+            board.get_occupied_player(pleco::Player::White).0 & (1 << e8.0),
+            0
+        );
+    }
+    #[test]
+    fn moves_san_covers_captures_disambiguation_castling_and_mate() {
+        let mut game = ChessGameBuilder::new().build();
+        for uci_move in [
+            "e2e4", "d7d5", "e4d5", "g8f6", "g1f3", "f6d5", "d2d4", "c8f5", "f1d3", "e7e6", "e1g1",
+            "b8c6", "b1d2",
+        ] {
+            game = game
+                .play_move(Move::new(String::from(uci_move), 0))
+                .unwrap();
+        }
+        let san = game.moves_san();
+        assert_eq!(san[2], "exd5");
+        assert_eq!(san[5], "Nxd5");
+        assert_eq!(san[10], "O-O");
+        assert_eq!(san[12], "Nbd2");
+        let mate = ChessGameBuilder::new()
+            .with_pgn_movetext("1. f3 e5 2. g4 Qh4#")
+            .unwrap()
+            .build();
+        assert_eq!(mate.moves_san(), vec!["f3", "e5", "g4", "Qh4#"]);
+    }
+    #[test]
+    fn play_san_resolves_moves() {
+        let game = ChessGameBuilder::new()
+            .build()
+            .play_san("e4", 0)
+            .unwrap()
+            .play_san("e5", 0)
+            .unwrap();
+        assert_eq!(
+            game.clone().play_san("Ke2", 0).unwrap().moves[2].uci_move,
+            "e1e2"
+        );
+        let game = game
+            .play_san("Nf3", 0)
+            .unwrap()
+            .play_san("Nc6", 0)
+            .unwrap()
+            .play_san("Bc4", 0)
+            .unwrap()
+            .play_san("Nf6", 0)
+            .unwrap()
+            .play_san("O-O", 1500)
+            .unwrap();
+        assert_eq!(game.moves[6].uci_move, "e1g1");
+        assert_eq!(game.moves[6].time_taken, 1500);
+        let blocked = ChessGameBuilder::new().build();
+        assert_eq!(
+            blocked.play_san("Ke2", 0).err(),
+            Some(ChessError::IllegalSan(String::from("Ke2")))
+        );
+        let ambiguous = ChessGameBuilder::new()
+            .with_pgn_movetext("1. d4 d5 2. Nf3 Nf6")
+            .unwrap()
+            .build();
+        assert_eq!(
+            ambiguous.play_san("Nd2", 0).err(),
+            Some(ChessError::AmbiguousSan(String::from("Nd2")))
+        );
+    }
+    #[test]
+    fn pgn_export_parses_back() {
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5 2. Bc4 Nc6 3. Qh5 Nf6 4. Qxf7#")
+            .unwrap()
+            .build();
+        let pgn = game.to_pgn();
+        assert!(pgn.starts_with("[Event \"?\"]\n"));
+        assert!(pgn.contains("[Result \"1-0\"]\n"));
+        assert!(!pgn.contains("[FEN"));
+        let (_, movetext) = pgn.split_once("\n\n").unwrap();
+        assert_eq!(movetext, "1. e4 e5 2. Bc4 Nc6 3. Qh5 Nf6 4. Qxf7# 1-0\n");
+        let parsed = ChessGameBuilder::new()
+            .with_pgn_movetext(movetext)
+            .unwrap()
+            .build();
+        let uci = |game: &ChessGame| {
+            game.moves
+                .iter()
+                .map(|mov| mov.uci_move.clone())
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(uci(&parsed), uci(&game));
+        let from_black = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("4k3/8/8/8/8/8/4P3/4K3 b - - 0 12").unwrap())
+            .with_pgn_movetext("12... Kd7 13. e4")
+            .unwrap()
+            .build();
+        let pgn = from_black.to_pgn();
+        assert!(pgn.contains("[SetUp \"1\"]\n[FEN \"4k3/8/8/8/8/8/4P3/4K3 b - - 0 12\"]\n"));
+        assert!(pgn.ends_with("\n\n12... Kd7 13. e4 *\n"));
+    }
+    #[test]
+    fn headerless_pgn_import() {
+        let game = ChessGame::from_pgn("1. e4 e5 2. Nf3 {a comment} (2. f4 exf4) Nc6 *").unwrap();
+        let uci: Vec<&str> = game.moves.iter().map(|mov| mov.uci_move()).collect();
+        assert_eq!(uci, vec!["e2e4", "e7e5", "g1f3", "b8c6"]);
+        assert!(game.moves.iter().all(|mov| mov.time_taken == 0));
+        assert_eq!(
+            ChessGame::from_pgn("1. e4 e5 2. Ke3").err(),
+            Some(ChessError::IllegalSan(String::from("Ke3")))
+        );
+    }
+    #[test]
+    fn pgn_import_from_fen_with_clocks() {
+        let pgn = "[Event \"Casual\"]\n\
+                   [SetUp \"1\"]\n\
+                   [FEN \"4k3/8/8/8/8/8/4P3/4K3 w - - 0 1\"]\n\
+                   [TimeControl \"60+1\"]\n\
+                   \n\
+                   1. e4 {[%clk 0:00:58]} Kd7 {[%clk 0:00:57]} 2. Kd2 {[%clk 0:00:50]} *\n";
+        let game = ChessGame::from_pgn(pgn).unwrap();
+        assert_eq!(game.initial_board.fen(), "4k3/8/8/8/8/8/4P3/4K3 w - - 0 1");
+        let times: Vec<u32> = game.moves.iter().map(|mov| mov.time_taken).collect();
+        assert_eq!(times, vec![3000, 4000, 9000]);
+        assert_eq!(game.time_limit, 60_000);
+        assert_eq!(game.time_control, TimeControl::Fischer(1000));
+        let untimed = ChessGame::from_pgn("1. e4 *").unwrap();
+        assert_eq!(untimed.time_limit, 0);
+        assert_eq!(untimed.time_control, TimeControl::SuddenDeath);
+    }
+    #[test]
+    fn pgn_import_rejects_bad_clock_comments() {
+        for clock in [
+            "9999:00:00",
+            "0:00:-5",
+            "0:00:NaN",
+            "1193:02:47.296",
+            "1:2:3:4",
+        ] {
+            let pgn = format!("1. e4 {{[%clk {}]}} *\n", clock);
+            assert!(
+                matches!(ChessGame::from_pgn(&pgn), Err(ChessError::InvalidPgn(_))),
+                "{}",
+                clock
+            );
+        }
+        assert_eq!(parse_clock_comment("[%clk 1193:02:47.295]"), Ok(u32::MAX));
+        assert_eq!(parse_clock_comment(" [%clk 0:01:02.5] "), Ok(62_500));
+    }
+    #[test]
+    fn pgn_import_rejects_bad_time_control() {
+        for time_control in ["4294968+0", "60+4294968", "60+x", "99999999999"] {
+            let pgn = format!("[TimeControl \"{}\"]\n\n1. e4 *\n", time_control);
+            assert!(
+                matches!(ChessGame::from_pgn(&pgn), Err(ChessError::InvalidPgn(_))),
+                "{}",
+                time_control
+            );
+        }
+        for time_control in ["?", "-", "40/9000"] {
+            let pgn = format!("[TimeControl \"{}\"]\n\n1. e4 *\n", time_control);
+            assert_eq!(ChessGame::from_pgn(&pgn).unwrap().moves.len(), 1);
+        }
+    }
+    #[test]
+    fn metadata_serialization_round_trip() {
+        let game = ChessGameBuilder::new()
+            .with_white_name("Carlsen")
+            .with_black_name("Nakamura")
+            .with_event("Casual Blitz")
+            .with_date("2024.01.05")
+            .build();
+        let json = serde_json::to_string(&game).unwrap();
+        let decoded: ChessGame = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.white_name.as_deref(), Some("Carlsen"));
+        assert_eq!(decoded.black_name.as_deref(), Some("Nakamura"));
+        assert_eq!(decoded.event.as_deref(), Some("Casual Blitz"));
+        assert_eq!(decoded.site, None);
+        assert!(game.to_pgn().contains("[White \"Carlsen\"]\n"));
+        let from_pgn = ChessGame::from_pgn(&game.to_pgn()).unwrap();
+        assert_eq!(from_pgn.black_name.as_deref(), Some("Nakamura"));
+        assert_eq!(from_pgn.round, None);
+        let from_token = ChessGame::from_share_token(&game.to_share_token().unwrap()).unwrap();
+        assert_eq!(from_token.date.as_deref(), Some("2024.01.05"));
+        // games saved before the metadata fields existed
+        let old_json = r#"{"initial_board":"rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1","moves":[],"start_time":0,"time_limit":0,"increment":0}"#;
+        let old: ChessGame = serde_json::from_str(old_json).unwrap();
+        assert_eq!(old.white_name, None);
+        assert_eq!(old.event, None);
+    }
+    #[test]
+    fn deserialize_invalid_fen_is_error() {
+        let json = r#"{"initial_board":"not a fen","moves":[],"start_time":0,"time_limit":0,"increment":0}"#;
+        assert!(serde_json::from_str::<ChessGame>(json).is_err());
+        let json = r#"["xyz",[],0,0,0]"#;
+        let err = serde_json::from_str::<ChessGame>(json).err().unwrap();
+        assert!(err.to_string().contains("invalid fen"), "{}", err);
+        let json = r#"["xyz",[],0,0,{"Bronstein":3000}]"#;
+        let err = serde_json::from_str::<ChessGame>(json).err().unwrap();
+        assert!(err.to_string().contains("invalid fen"), "{}", err);
+    }
+    #[test]
+    fn sequence_accepts_legacy_increment() {
+        let fen = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+        let json = format!(r#"["{}",[],0,60000,2000]"#, fen);
+        let game: ChessGame = serde_json::from_str(&json).unwrap();
+        assert_eq!(game.time_control, TimeControl::Fischer(2000));
+        let json = format!(r#"["{}",[],0,60000,"SuddenDeath"]"#, fen);
+        let game: ChessGame = serde_json::from_str(&json).unwrap();
+        assert_eq!(game.time_control, TimeControl::SuddenDeath);
+    }
+    #[test]
+    fn deserialize_errors_name_the_field() {
+        let json = r#"{"initial_board":"rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1","moves":[],"start_time":0,"increment":0}"#;
+        let err = serde_json::from_str::<ChessGame>(json).err().unwrap();
+        assert!(err.to_string().contains("time_limit"), "{}", err);
+        let json = r#"{"initial_board":"rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1","moves":[],"start_time":0,"time_limit":0,"increment":0,"increment":5}"#;
+        let err = serde_json::from_str::<ChessGame>(json).err().unwrap();
+        assert!(err.to_string().contains("increment"), "{}", err);
+    }
+    #[test]
+    fn serialized_keys_deserialize() {
+        let mut game = ChessGameBuilder::new()
+            .with_time_limit(1000 * 60 * 5)
+            .with_increment(2000)
+            .with_black_time_limit(1000 * 60 * 3)
+            .with_draw_claim_window(3)
+            .with_white_name("Anand")
+            .with_round("4")
+            .build();
+        game = game
+            .play_move(Move::new(String::from("e2e4"), 1500))
+            .unwrap();
+        let value = serde_json::to_value(&game).unwrap();
+        for key in [
+            "initial_board",
+            "start_time",
+            "time_limit",
+            "black_time_limit",
+        ] {
+            assert!(value.get(key).is_some(), "missing {}", key);
+        }
+        let decoded: ChessGame = serde_json::from_str(&value.to_string()).unwrap();
+        assert_eq!(decoded.time_limit, 1000 * 60 * 5);
+        assert_eq!(decoded.time_control, TimeControl::Fischer(2000));
+        assert_eq!(decoded.start_time, game.start_time);
+        assert_eq!(decoded.black_time_limit, Some(1000 * 60 * 3));
+        assert_eq!(decoded.draw_claim_window, Some(3));
+        assert_eq!(decoded.round.as_deref(), Some("4"));
+        assert_eq!(decoded.current_fen(), game.current_fen());
+    }
+    #[test]
+    fn time_control_modes() {
+        // white's moves take more than, exactly and less than the 1s delay or increment
+        let play = |time_control: TimeControl| {
+            let mut game = ChessGameBuilder::new()
+                .with_time_limit(60_000)
+                .with_time_control(time_control)
+                .build();
+            for (uci, time_taken) in [
+                ("e2e4", 1500),
+                ("e7e5", 0),
+                ("g1f3", 1000),
+                ("b8c6", 0),
+                ("f1c4", 500),
+            ] {
+                game = game
+                    .play_move(Move::new(String::from(uci), time_taken))
+                    .unwrap();
+            }
+            game.start_time = 0;
+            game
+        };
+        let sudden_death = play(TimeControl::SuddenDeath);
+        let fischer = play(TimeControl::Fischer(1000));
+        let bronstein = play(TimeControl::Bronstein(1000));
+        let simple_delay = play(TimeControl::SimpleDelay(1000));
+        assert_eq!(sudden_death.compute_white_moves_time_with_increment(), 3000);
+        // 1500 - 1000, then 500 + 1000 - 1000, then 500 + 500 - 1000
+        assert_eq!(fischer.compute_white_moves_time_with_increment(), 0);
+        // only the first move went past the delay
+        assert_eq!(bronstein.compute_white_moves_time_with_increment(), 500);
+        assert_eq!(simple_delay.compute_white_moves_time_with_increment(), 500);
+        assert_eq!(
+            bronstein.remaining_time_annotations()[0],
+            (pleco::Player::White, 59_500)
+        );
+
+        // black to move, 3000ms of settled moves since the start
+        for (elapsed, bronstein_clock, delay_clock) in [
+            (500, 59_500, 60_000),
+            (1000, 59_000, 60_000),
+            (1500, 58_500, 59_500),
+        ] {
+            let now = 3000 + elapsed;
+            assert_eq!(
+                bronstein.player_clock(pleco::Player::Black, now),
+                bronstein_clock
+            );
+            assert_eq!(
+                fischer.player_clock(pleco::Player::Black, now),
+                bronstein_clock
+            );
+            assert_eq!(
+                simple_delay.player_clock(pleco::Player::Black, now),
+                delay_clock
+            );
+        }
+
+        let decoded: ChessGame =
+            serde_json::from_str(&serde_json::to_string(&simple_delay).unwrap()).unwrap();
+        assert_eq!(decoded.time_control, TimeControl::SimpleDelay(1000));
+        let decoded = ChessGame::from_share_token(&bronstein.to_share_token().unwrap()).unwrap();
+        assert_eq!(decoded.time_control, TimeControl::Bronstein(1000));
+        let decoded = ChessGame::from_share_token(&fischer.to_share_token().unwrap()).unwrap();
+        assert_eq!(decoded.time_control, TimeControl::Fischer(1000));
+    }
+    #[test]
+    fn time_control_notation() {
+        let game = ChessGameBuilder::new()
+            .with_time_control_str("3+2")
+            .unwrap()
+            .build();
+        assert_eq!(game.time_limit, 3 * 60 * 1000);
+        assert_eq!(game.time_control, TimeControl::Fischer(2000));
+        let game = ChessGameBuilder::new()
+            .with_time_control_str("5|0")
+            .unwrap()
+            .build();
+        assert_eq!(game.time_limit, 5 * 60 * 1000);
+        assert_eq!(game.time_control, TimeControl::SuddenDeath);
+        let game = ChessGameBuilder::new()
+            .with_time_control_str("180000ms+2000ms")
+            .unwrap()
+            .build();
+        assert_eq!(game.time_limit, 180_000);
+        assert_eq!(game.time_control, TimeControl::Fischer(2000));
+        for notation in ["3", "3+x", "+2", "5|0|1", "99999999+0"] {
+            assert!(matches!(
+                ChessGameBuilder::new().with_time_control_str(notation),
+                Err(ChessError::InvalidTimeControl(_))
+            ));
+        }
+    }
+    #[test]
+    fn staged_time_control_adds_time_after_move_40() {
+        let minute = 60 * 1000;
+        let stages = vec![
+            TimeControlStage {
+                moves: Some(40),
+                base_time: 90 * minute,
+                increment: 30 * 1000,
+            },
+            TimeControlStage {
+                moves: None,
+                base_time: 30 * minute,
+                increment: 30 * 1000,
+            },
+        ];
+        let mut game = ChessGameBuilder::new()
+            .with_time_control_stages(stages.clone())
+            .build();
+        assert_eq!(game.time_limit, 90 * minute);
+        // white spends 2 minutes on every move, black answers instantly
+        for _ in 0..21 {
+            for (uci, time_taken) in [
+                ("g1f3", 2 * minute),
+                ("g8f6", 0),
+                ("f3g1", 2 * minute),
+                ("f6g8", 0),
+            ] {
+                game = game
+                    .play_move(Move::new(String::from(uci), time_taken))
+                    .unwrap();
+            }
+        }
+        let annotations = game.remaining_time_annotations();
+        // 39 moves at 1:30 net each
+        assert_eq!(
+            annotations[76],
+            (pleco::Player::White, (90 * minute - 39 * 90 * 1000) as i64)
+        );
+        // the 40th move completes the first stage and adds 30 minutes
+        assert_eq!(
+            annotations[78],
+            (pleco::Player::White, (60 * minute) as i64)
+        );
+        assert_eq!(
+            annotations[79],
+            (pleco::Player::Black, (120 * minute) as i64)
+        );
+        assert_eq!(
+            annotations[80],
+            (pleco::Player::White, (60 * minute - 90 * 1000) as i64)
+        );
+        game.start_time = 0;
+        let now = 42 * 2 * minute as u64;
+        assert_eq!(
+            game.player_clock(pleco::Player::White, now),
+            (60 * minute - 2 * 90 * 1000) as i64
+        );
+        assert_eq!(
+            game.player_clock(pleco::Player::Black, now),
+            (120 * minute) as i64
+        );
+        let decoded = ChessGame::from_share_token(&game.to_share_token().unwrap()).unwrap();
+        assert_eq!(decoded.time_control_stages, stages);
+        let decoded: ChessGame =
+            serde_json::from_str(&serde_json::to_string(&game).unwrap()).unwrap();
+        assert_eq!(decoded.time_control_stages, stages);
+    }
+    #[test]
+    fn time_remaining_reaches_zero_at_the_limit() {
+        let limit = 60_000;
+        let after_white_move = |time_taken: u32| {
+            ChessGameBuilder::new()
+                .with_time_limit(limit)
+                .build()
+                .play_move(Move::new(String::from("e2e4"), time_taken))
+                .unwrap()
+        };
+        let game = after_white_move(1000);
+        assert_eq!(game.white_time_remaining(), 59_000);
+        assert!(game.black_time_remaining() <= limit as i64);
+        assert!(!game.is_white_time_over());
+        let game = after_white_move(limit - 1);
+        assert_eq!(game.white_time_remaining(), 1);
+        assert!(!game.is_white_time_over());
+        let game = after_white_move(limit);
+        assert_eq!(game.white_time_remaining(), 0);
+        assert!(game.is_white_time_over());
+        assert_eq!(game.result(), GameResult::BlackWins);
+        let game = after_white_move(limit + 1);
+        assert_eq!(game.white_time_remaining(), -1);
+        assert!(game.is_white_time_over());
+    }
+    #[test]
+    fn start_time_keeps_the_full_epoch_time() {
+        let game = ChessGameBuilder::new().with_time_limit(60_000).build();
+        assert!(game.start_time > u32::MAX as u64);
+        assert!(game.compute_current_move_time() < 1000);
+        let decoded = ChessGame::from_share_token(&game.to_share_token().unwrap()).unwrap();
+        assert_eq!(decoded.start_time, game.start_time);
+        // saves from before the widening hold the low 32 bits
+        let mut json = serde_json::to_value(&game).unwrap();
+        json["start_time"] = serde_json::json!(game.start_time as u32);
+        json.as_object_mut().unwrap().remove("version");
+        let decoded: ChessGame = serde_json::from_str(&json.to_string()).unwrap();
+        assert_eq!(decoded.start_time, game.start_time);
+        assert!(decoded.compute_current_move_time() < 1000);
+        let window = 1u64 << 32;
+        assert_eq!(
+            restore_truncated_start_time(5, 3 * window + 10),
+            3 * window + 5
+        );
+        assert_eq!(
+            restore_truncated_start_time(20, 3 * window + 10),
+            2 * window + 20
+        );
+    }
+    #[test]
+    fn side_to_move_alternates() {
+        let game = ChessGameBuilder::new().build();
+        assert_eq!(game.side_to_move(), pleco::Player::White);
+        let game = game.play_move(Move::new(String::from("e2e4"), 0)).unwrap();
+        assert_eq!(game.side_to_move(), pleco::Player::Black);
+    }
+    #[test]
+    fn start_position_has_twenty_legal_moves() {
+        let game = ChessGameBuilder::new().build();
+        let legal_moves = game.legal_moves();
+        assert_eq!(legal_moves.len(), 20);
+        assert!(legal_moves.contains(&String::from("g1f3")));
+        let legal_moves_san = game.legal_moves_san();
+        assert_eq!(legal_moves_san.len(), 20);
+        assert!(legal_moves_san.contains(&String::from("Nf3")));
+        assert!(legal_moves_san.contains(&String::from("e4")));
+    }
+    #[test]
+    fn check_detection() {
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 f5 2. Qh5+")
+            .unwrap()
+            .build();
+        assert!(game.is_check());
+        assert!(!game.is_checkmate());
+        assert!(!game.is_check_at_turn(2));
+
+        let scholars_mate = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5 2. Bc4 Nc6 3. Qh5 Nf6 4. Qxf7#")
+            .unwrap()
+            .build();
+        assert!(scholars_mate.is_check());
+        assert!(scholars_mate.is_checkmate());
+        assert!(scholars_mate.is_check_at_turn(7));
+        assert!(!scholars_mate.is_check_at_turn(6));
+    }
+    #[test]
+    fn move_annotations_round_trip() {
+        let game = ChessGameBuilder::new()
+            .build()
+            .play_move(Move::new(String::from("e2e4"), 0).with_nag(1))
+            .unwrap()
+            .play_move(Move::new(String::from("e7e5"), 0).with_comment("Symmetrical"))
+            .unwrap()
+            .play_move(Move::new(String::from("g1f3"), 0).with_comment("Develops"))
+            .unwrap()
+            .play_move(Move::new(String::from("b8c6"), 0))
+            .unwrap();
+        let json = serde_json::to_string(&game).unwrap();
+        let decoded: ChessGame = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.moves()[0].nag(), Some(1));
+        assert_eq!(decoded.moves()[1].comment(), Some("Symmetrical"));
+        assert_eq!(decoded.moves()[3].comment(), None);
+        assert!(game
+            .to_pgn()
+            .contains("1. e4 $1 e5 {Symmetrical} 2. Nf3 {Develops} 2... Nc6 *"));
+        let old: Move = serde_json::from_str(r#"{"uci_move":"e2e4","time_taken":5}"#).unwrap();
+        assert_eq!(old.comment(), None);
+        assert_eq!(old.nag(), None);
+    }
+    #[test]
+    fn positions_replay_the_game() {
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5 2. Nf3 Nc6 3. Bb5")
+            .unwrap()
+            .build();
+        let positions: Vec<Board> = game.positions().collect();
+        assert_eq!(positions.len(), 6);
+        assert_eq!(positions[0].fen(), Board::start_pos().fen());
+        assert_eq!(positions[2].fen(), game.compute_board_at_turn(2).fen());
+        assert_eq!(
+            positions.last().unwrap().fen(),
+            game.compute_current_board().fen()
+        );
+    }
+    #[test]
+    fn single_pass_clock_sums_match_replaying_each_turn() {
+        let mut game = ChessGameBuilder::new()
+            .with_time_limit(1000 * 60 * 60)
+            .with_increment(700)
+            .build();
+        for index in 0..200u32 {
+            let uci = ["g1f3", "g8f6", "f3g1", "f6g8"][index as usize % 4];
+            game = game
+                .play_move(Move::new(String::from(uci), index * 37 % 2000))
+                .unwrap();
+        }
+        // the previous implementation replayed the board up to every move to find its mover
+        let mut white = (0, 0);
+        let mut black = (0, 0);
+        for (turn, mov) in game.moves.iter().enumerate() {
+            let totals = match game.compute_board_at_turn(turn as u16 + 1).turn() {
+                pleco::Player::Black => &mut white,
+                pleco::Player::White => &mut black,
+            };
+            totals.0 += mov.time_taken;
+            totals.1 = (totals.1 + mov.time_taken).saturating_sub(700);
+        }
+        assert_eq!(game.compute_white_moves_pure_time(), white.0);
+        assert_eq!(game.compute_black_moves_pure_time(), black.0);
+        assert_eq!(game.compute_white_moves_time_with_increment(), white.1);
+        assert_eq!(game.compute_black_moves_time_with_increment(), black.1);
+    }
+    #[test]
+    fn clock_sums_when_black_moves_first() {
+        let mut game = ChessGameBuilder::new()
+            .with_initial_board(
+                Board::from_fen("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1")
+                    .unwrap(),
+            )
+            .with_time_limit(60_000)
+            .build();
+        for (uci, time_taken) in [("e7e5", 1000), ("g1f3", 2000), ("b8c6", 3000)] {
+            game = game
+                .play_move(Move::new(String::from(uci), time_taken))
+                .unwrap();
+        }
+        assert_eq!(game.player_at_ply(0), pleco::Player::Black);
+        assert_eq!(game.player_at_ply(1), pleco::Player::White);
+        assert_eq!(game.compute_black_moves_pure_time(), 4000);
+        assert_eq!(game.compute_white_moves_pure_time(), 2000);
+        assert_eq!(game.compute_black_moves_time_with_increment(), 4000);
+        assert_eq!(game.compute_white_moves_time_with_increment(), 2000);
+        let annotations = game.remaining_time_annotations();
+        assert_eq!(annotations[0], (pleco::Player::Black, 59_000));
+        assert_eq!(annotations[2], (pleco::Player::Black, 56_000));
+        game.start_time = 0;
+        assert_eq!(game.player_clock(pleco::Player::White, 6000), 58_000);
+    }
+    #[test]
+    fn first_move_time_lands_on_black_clock_when_black_starts() {
+        let black_to_move =
+            Board::from_fen("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1").unwrap();
+        let game = ChessGameBuilder::new()
+            .with_initial_board(black_to_move)
+            .with_time_limit(4000)
+            .build()
+            .play_move(Move::new(String::from("e7e5"), 5000))
+            .unwrap();
+        assert_eq!(game.compute_black_moves_pure_time(), 5000);
+        assert_eq!(game.compute_white_moves_pure_time(), 0);
+        assert_eq!(game.black_time_remaining(), -1000);
+        assert_eq!(
+            game.remaining_time_annotations(),
+            vec![(pleco::Player::Black, -1000)]
+        );
+        assert_eq!(game.flagged_player(), Some(pleco::Player::Black));
+    }
+    #[test]
+    fn resignation_ends_the_game() {
+        let game = ChessGameBuilder::new()
+            .build()
+            .play_move(Move::new(String::from("e2e4"), 0))
+            .unwrap()
+            .resign(pleco::Player::White);
+        assert_eq!(game.result(), GameResult::BlackWins);
+        assert!(matches!(
+            game.clone().play_move(Move::new(String::from("e7e5"), 0)),
+            Err(ChessError::GameOver)
+        ));
+        // the first result stands
+        let game = game.resign(pleco::Player::Black).agree_draw();
+        assert_eq!(game.result(), GameResult::BlackWins);
+        assert!(game.to_pgn().contains("[Result \"0-1\"]"));
+        let decoded: ChessGame =
+            serde_json::from_str(&serde_json::to_string(&game).unwrap()).unwrap();
+        assert_eq!(decoded.result(), GameResult::BlackWins);
+        let decoded = ChessGame::from_share_token(&game.to_share_token().unwrap()).unwrap();
+        assert_eq!(decoded.result(), GameResult::BlackWins);
+        // only a resignation or an agreed draw can be stored as the result
+        let mut json = serde_json::to_value(&game).unwrap();
+        for result in [
+            serde_json::json!("Ongoing"),
+            serde_json::json!({ "Draw": "Stalemate" }),
+        ] {
+            json["result_override"] = result;
+            let err = serde_json::from_str::<ChessGame>(&json.to_string())
+                .err()
+                .unwrap();
+            assert!(err.to_string().contains("result_override"), "{}", err);
+        }
+        let mut bytes = game.to_bytes().unwrap();
+        for code in [0, 3] {
+            *bytes.last_mut().unwrap() = code;
+            assert!(ChessGame::from_bytes(&bytes).is_err());
+        }
+    }
+    #[test]
+    fn agreed_draw_ends_the_game() {
+        let game = ChessGameBuilder::new().build().agree_draw();
+        assert_eq!(game.result(), GameResult::Draw(DrawReason::Agreement));
+        assert!(game
+            .clone()
+            .play_move(Move::new(String::from("e2e4"), 0))
+            .is_err());
+        let decoded = ChessGame::from_share_token(&game.to_share_token().unwrap()).unwrap();
+        assert_eq!(decoded.result(), GameResult::Draw(DrawReason::Agreement));
+        // a mated game cannot be agreed drawn afterwards
+        let mated = ChessGameBuilder::new()
+            .with_pgn_movetext("1. f3 e5 2. g4 Qh4#")
+            .unwrap()
+            .build()
+            .agree_draw();
+        assert_eq!(mated.result(), GameResult::BlackWins);
+    }
+    #[test]
+    fn material_balance_and_captures() {
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 d5 2. exd5 Qxd5 3. Nc3 Qxa2 4. Rxa2")
+            .unwrap()
+            .build();
+        assert_eq!(game.material_balance(), 9 - 1);
+        assert_eq!(
+            game.captured_pieces(),
+            (
+                vec![pleco::PieceType::P, pleco::PieceType::P],
+                vec![pleco::PieceType::P, pleco::PieceType::Q]
+            )
+        );
+        let rook_up = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("4k3/8/8/8/8/8/8/R3K3 w - - 0 1").unwrap())
+            .build();
+        assert_eq!(rook_up.material_balance(), 5);
+        assert_eq!(rook_up.captured_pieces(), (vec![], vec![]));
+
+        // the pawn promotes capturing a rook: no pawn was captured
+        let promotion = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("r3k3/1P6/8/8/8/8/8/4K3 w - - 0 1").unwrap())
+            .build()
+            .play_move(Move::new(String::from("b7a8q"), 0))
+            .unwrap();
+        assert_eq!(promotion.material_balance(), 9);
+        assert_eq!(
+            promotion.captured_pieces(),
+            (vec![], vec![pleco::PieceType::R])
+        );
+        // the promoted queen is then captured
+        let recaptured = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("3rk3/1P6/8/8/8/8/8/4K3 w - - 0 1").unwrap())
+            .with_pgn_movetext("1. b8=Q Rxb8")
+            .unwrap()
+            .build();
+        assert_eq!(recaptured.material_balance(), -5);
+        assert_eq!(
+            recaptured.captured_pieces(),
+            (vec![pleco::PieceType::Q], vec![])
+        );
+    }
+    #[test]
+    fn perft_from_the_start_position() {
+        let game = ChessGameBuilder::new().build();
+        assert_eq!(game.perft(0), 1);
+        assert_eq!(game.perft(1), 20);
+        assert_eq!(game.perft(2), 400);
+        assert_eq!(game.perft(3), 8902);
+        let divide = game.perft_divide(2);
+        assert_eq!(divide.len(), 20);
+        assert!(divide.iter().all(|(_, nodes)| *nodes == 20));
+        assert!(divide.iter().any(|(uci, _)| uci == "e2e4"));
+    }
+    #[test]
+    fn last_move_info_reports_the_move() {
+        let game = ChessGameBuilder::new().build();
+        assert_eq!(game.last_move_info(), None);
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 d5 2. exd5")
+            .unwrap()
+            .build();
+        assert_eq!(
+            game.last_move_info(),
+            Some(MoveInfo {
+                san: String::from("exd5"),
+                is_capture: true,
+                is_check: false,
+                is_castle: false,
+                is_promotion: false,
+            })
+        );
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5 2. Nf3 Nc6 3. Bc4 Bc5 4. O-O")
+            .unwrap()
+            .build();
+        let info = game.last_move_info().unwrap();
+        assert_eq!(info.san, "O-O");
+        assert!(info.is_castle);
+        assert!(!info.is_capture);
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 f5 2. Qh5+")
+            .unwrap()
+            .build();
+        assert!(game.last_move_info().unwrap().is_check);
+    }
+    #[test]
+    fn render_start_position() {
+        let game = ChessGameBuilder::new().build();
+        assert_eq!(
+            game.render_ascii(),
+            "8 r n b q k b n r\n\
+             7 p p p p p p p p\n\
+             6 . . . . . . . .\n\
+             5 . . . . . . . .\n\
+             4 . . . . . . . .\n\
+             3 . . . . . . . .\n\
+             2 P P P P P P P P\n\
+             1 R N B Q K B N R\n\
+             \x20 a b c d e f g h\n"
+        );
+        let unicode = game.render_unicode();
+        assert!(unicode.starts_with("8 ♜ ♞ ♝ ♛ ♚ ♝ ♞ ♜\n"));
+        assert!(unicode.contains("1 ♖ ♘ ♗ ♕ ♔ ♗ ♘ ♖\n"));
+        let flipped = game
+            .play_move(Move::new(String::from("e2e4"), 0))
+            .unwrap()
+            .render_from(pleco::Player::Black, false);
+        assert!(flipped.starts_with("1 R N B K Q B N R\n2 P P P . P P P P\n"));
+        assert!(flipped.contains("4 . . . P . . . .\n"));
+        assert!(flipped.ends_with("  h g f e d c b a\n"));
+    }
+    #[test]
+    fn fen_at_turn_counts_the_move_counters() {
+        let game = ChessGameBuilder::new()
+            .with_pgn_movetext("1. e4 e5 2. Nf3 Nc6 3. Bb5 a6 4. Bxc6")
+            .unwrap()
+            .build();
+        let counters = |turn: u16| {
+            let fen = game.fen_at_turn(turn);
+            let fields: Vec<String> = fen.split(' ').skip(4).map(String::from).collect();
+            (fields[0].clone(), fields[1].clone())
+        };
+        assert_eq!(counters(0), (String::from("0"), String::from("1")));
+        assert_eq!(counters(2), (String::from("0"), String::from("2")));
+        assert_eq!(counters(4), (String::from("2"), String::from("3")));
+        assert_eq!(counters(5), (String::from("3"), String::from("3")));
+        // the capture resets the halfmove clock
+        assert_eq!(counters(7), (String::from("0"), String::from("4")));
+        assert_eq!(game.fen_at_turn(100), game.fen_at_turn(7));
+
+        let black_to_move = ChessGameBuilder::new()
+            .with_initial_board(Board::from_fen("4k3/8/8/8/8/8/8/R3K3 b - - 12 30").unwrap())
+            .with_pgn_movetext("30... Kd7 31. Ra7+")
+            .unwrap()
+            .build();
+        assert!(black_to_move.fen_at_turn(1).ends_with(" 13 31"));
+        assert!(black_to_move.fen_at_turn(2).ends_with(" 14 31"));
+    }
+    #[test]
+    fn chess960_starting_positions() {
+        assert_eq!(
+            chess960_fen(518).unwrap(),
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w HAha - 0 1"
+        );
+        assert_eq!(
+            chess960_fen(0).unwrap(),
+            "bbqnnrkr/pppppppp/8/8/8/8/PPPPPPPP/BBQNNRKR w HFhf - 0 1"
+        );
+        assert_eq!(chess960_fen(960), None);
+        let classical = ChessGameBuilder::new().with_chess960(518).unwrap().build();
+        assert_eq!(classical.current_fen(), Board::start_pos().fen());
+        // bishops on opposite colours and the king between the rooks in every position
+        for position_id in [0, 1, 100, 361, 959] {
+            let rank = chess960_back_rank(position_id).unwrap();
+            let files = |piece: char| -> Vec<usize> {
+                (0..8).filter(|file| rank[*file] == piece).collect()
+            };
+            assert_eq!(files('B').iter().map(|file| file % 2).sum::<usize>(), 1);
+            let (rooks, king) = (files('R'), files('K'));
+            assert!(rooks[0] < king[0] && king[0] < rooks[1]);
+            assert_eq!(files('N').len(), 2);
+            assert_eq!(files('Q').len(), 1);
+        }
+        // 692 is RBBQKNNR: king on e1 and rooks in the corners, so castling is supported
+        let mut game = ChessGameBuilder::new().with_chess960(692).unwrap().build();
+        assert!(game.current_fen().starts_with("rbbqknnr/pppppppp/"));
+        assert!(game.current_fen().contains(" KQkq "));
+        for uci in ["f1e3", "f8e6", "g1f3", "g8f6", "e1g1", "e8g8"] {
+            game = game.play_move(Move::new(uci.to_string(), 0)).unwrap();
+        }
+        assert!(game
+            .current_fen()
+            .starts_with("rbbq1rk1/pppppppp/4nn2/8/8/4NN2/PPPPPPPP/RBBQ1RK1 w - "));
+        assert!(matches!(
+            ChessGameBuilder::new().with_chess960(960),
+            Err(ChessError::InvalidChess960Position(960))
+        ));
+    }
+    #[test]
+    fn chess960_castles_from_non_standard_position() {
+        // 0 is BBQNNRKR: king on g1 between rooks on f1 and h1
+        let mut game = ChessGameBuilder::new().with_chess960(0).unwrap().build();
+        assert!(game.current_fen().contains(" w HFhf - "));
+        // the f1 rook is in the way of the h1 rook
+        assert_eq!(
+            game.castling_illegality_reason("g1h1"),
+            Some(CastleError::PathBlocked)
+        );
+        for uci in [
+            "d1c3", "d8c6", "e1d3", "e8d6", "b2b3", "b7b6", "c1b2", "c8b7",
+        ] {
+            game = game.play_move(Move::new(uci.to_string(), 0)).unwrap();
+        }
+        assert!(game.legal_moves().contains(&String::from("g1f1")));
+        assert!(game.legal_moves_san().contains(&String::from("O-O-O")));
+        assert!(game
+            .clone()
+            .play_move(Move::new(String::from("g1h1"), 0))
+            .is_err());
+        game = game.play_move(Move::new(String::from("g1f1"), 0)).unwrap();
+        assert!(game
+            .current_fen()
+            .starts_with("bb3rkr/pqpppppp/1pnn4/8/8/1PNN4/PQPPPPPP/BBKR3R b hf - "));
+        game = game.play_san("O-O-O", 0).unwrap();
+        assert!(game.current_fen().contains("/BBKR3R w - - "));
+        assert_eq!(game.moves()[9].uci_move(), "g8f8");
+        assert_eq!(game.moves_san()[8..], ["O-O-O", "O-O-O"]);
+        assert!(game.last_move_info().unwrap().is_castle);
+        assert_eq!(game.check_invariants(), Ok(()));
+        let from_pgn = ChessGame::from_pgn(&game.to_pgn()).unwrap();
+        assert_eq!(from_pgn.current_fen(), game.current_fen());
+        let json = serde_json::to_string(&game).unwrap();
+        let from_json: ChessGame = serde_json::from_str(&json).unwrap();
+        assert_eq!(from_json.current_fen(), game.current_fen());
+        let from_bytes = ChessGame::from_bytes(&game.to_bytes().unwrap()).unwrap();
+        assert_eq!(from_bytes.current_fen(), game.current_fen());
+        // moving a rook gives up castling on its side only
+        let game = ChessGameBuilder::new()
+            .with_chess960(0)
+            .unwrap()
+            .with_pgn_movetext("1. h4 h5 2. Rh3")
+            .unwrap()
+            .build();
+        assert!(game.current_fen().contains(" b Fhf - "));
+    }
+    #[test]
+    fn move_time_statistics_per_side() {
+        let mut game = ChessGameBuilder::new().build();
+        assert_eq!(game.average_move_time(pleco::Player::White), None);
+        assert_eq!(game.slowest_move(pleco::Player::White), None);
+        for (uci, time_taken) in [
+            ("e2e4", 1000),
+            ("e7e5", 9000),
+            ("g1f3", 4000),
+            ("b8c6", 3000),
+            ("f1c4", 4000),
+        ] {
+            game = game
+                .play_move(Move::new(String::from(uci), time_taken))
+                .unwrap();
+        }
+        assert_eq!(game.average_move_time(pleco::Player::White), Some(3000));
+        assert_eq!(game.average_move_time(pleco::Player::Black), Some(6000));
+        assert_eq!(game.slowest_move(pleco::Player::White), Some((2, 4000)));
+        assert_eq!(game.fastest_move(pleco::Player::White), Some((0, 1000)));
+        assert_eq!(game.slowest_move(pleco::Player::Black), Some((1, 9000)));
+        assert_eq!(game.fastest_move(pleco::Player::Black), Some((3, 3000)));
+    }
+    #[test]
+    fn deserializes_every_serialization_version() {
+        let version_1 = r#"{"initial_board":"rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1","moves":[{"uci_move":"e2e4","time_taken":1500}],"start_time":5,"time_limit":60000,"increment":2000}"#;
+        let game: ChessGame = serde_json::from_str(version_1).unwrap();
+        assert_eq!(game.time_control, TimeControl::Fischer(2000));
+        assert!(game.start_time > u32::MAX as u64);
+        assert_eq!(game.moves().len(), 1);
+
+        let version_2 = r#"{"initial_board":"rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1","moves":[],"start_time":5,"time_limit":60000,"time_control":{"Bronstein":3000},"version":2}"#;
+        let game: ChessGame = serde_json::from_str(version_2).unwrap();
+        assert_eq!(game.time_control, TimeControl::Bronstein(3000));
+        assert_eq!(game.start_time, 5);
+        // version 2 has no increment fallback
+        let no_time_control = version_2.replace(
+            r#""time_control":{"Bronstein":3000},"#,
+            r#""increment":2000,"#,
+        );
+        assert!(serde_json::from_str::<ChessGame>(&no_time_control).is_err());
+
+        let written = serde_json::to_value(&game).unwrap();
+        assert_eq!(written["version"], 2);
+        let future = version_2.replace(r#""version":2"#, r#""version":3"#);
+        assert!(serde_json::from_str::<ChessGame>(&future).is_err());
+
+        // version 1 sequences: u32 start_time and integer increment, no version element
+        let now = current_millis();
+        let fen = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+        let version_1 = format!(r#"["{}",[],{},60000,2000]"#, fen, now as u32);
+        let game: ChessGame = serde_json::from_str(&version_1).unwrap();
+        assert_eq!(game.time_control, TimeControl::Fischer(2000));
+        assert_eq!(game.start_time, now);
+        let written = serde_json::to_value(&game).unwrap().to_string();
+        assert_eq!(
+            serde_json::from_str::<ChessGame>(&written)
+                .unwrap()
+                .start_time,
+            now
+        );
+        // later versions write a time control, never an integer increment
+        let mixed = format!(
+            r#"["{}",[],{},60000,2000,true,null,true,false,null,null,null,null,null,null,null,[],null,2]"#,
+            fen, now
+        );
+        let err = serde_json::from_str::<ChessGame>(&mixed).err().unwrap();
+        assert!(err.to_string().contains("integer increment"), "{}", err);
+        let err = serde_json::from_str::<ChessGame>(&future).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("unsupported serialization version 3"));
+    }
+    #[test]
+    fn binary_encoding_round_trip() {
+        let game = ChessGameBuilder::new()
+            .with_fen("r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3")
+            .unwrap()
+            .with_time_control_str("5+3")
+            .unwrap()
+            .with_black_time_limit(120_000)
+            .with_white_name("Polgar")
+            .with_draw_claim_window(4)
+            .build()
+            .play_move(Move::new(String::from("f1b5"), 1200))
+            .unwrap()
+            .play_move(Move::new(String::from("a7a6"), 800))
+            .unwrap()
+            .resign(pleco::Player::White);
+        let bytes = game.to_bytes().unwrap();
+        let decoded = ChessGame::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(&game).unwrap()
+        );
+        assert!(bytes.len() < serde_json::to_string(&game).unwrap().len() / 2);
+        assert!(ChessGame::from_bytes(&bytes[..bytes.len() - 3]).is_err());
+        // the flags record the result, so losing its byte is an error rather than no result
+        assert!(matches!(
+            ChessGame::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ChessError::InvalidBinaryData(_))
+        ));
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(ChessGame::from_bytes(&trailing).is_err());
+    }
+    #[test]
+    fn binary_encoding_rejects_oversized_values() {
+        // 255 bytes fit, but a 256th would otherwise cut the two-byte 'é' in half
+        let name = format!("{}é", "a".repeat(254));
+        let game = ChessGameBuilder::new()
+            .with_white_name(name.as_str())
+            .build();
+        assert_eq!(
+            game.to_bytes(),
+            Err(ChessError::TooLargeForBinary(String::from(
+                "metadata value"
+            )))
+        );
+        assert!(game.to_share_token().is_err());
+        let name = "a".repeat(255);
+        let game = ChessGameBuilder::new()
+            .with_white_name(name.as_str())
+            .build();
+        let decoded = ChessGame::from_bytes(&game.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.white_name, Some(name));
+        let game = ChessGameBuilder::new()
+            .with_time_control_stages(vec![TimeControlStage {
+                moves: Some(70_000),
+                base_time: 60_000,
+                increment: 0,
+            }])
+            .build();
+        assert!(game.to_bytes().is_err());
+    }
+    #[test]
+    fn bit_move_replay_matches_uci_replay() {
+        let game = ChessGameBuilder::new()
+            .with_fen("r3k2r/1P4p1/8/3pP3/8/8/8/R3K2R w KQkq d6 0 1")
+            .unwrap()
+            .build();
+        let mut game = game;
+        for uci in ["e5d6", "e8g8", "b7b8q", "a8b8", "e1c1", "g7g5"] {
+            game = game.play_move(Move::new(String::from(uci), 0)).unwrap();
+        }
+        let decoded: ChessGame =
+            serde_json::from_str(&serde_json::to_string(&game).unwrap()).unwrap();
+        let fields = serde_json::to_value(&game).unwrap();
+        let sequence = serde_json::json!([
+            fields["initial_board"],
+            fields["moves"],
+            fields["start_time"],
+            fields["time_limit"],
+            fields["time_control"]
+        ]);
+        let from_sequence: ChessGame = serde_json::from_str(&sequence.to_string()).unwrap();
+        assert_eq!(from_sequence.moves_san(), game.moves_san());
+        for replayed in [&game, &decoded, &from_sequence] {
+            assert!(replayed.moves().iter().all(|mov| mov.bit_move.is_some()));
+            let mut board = replayed.initial_board.clone();
+            let mut uci_positions = vec![board.fen()];
+            for mov in replayed.moves() {
+                assert!(board.apply_uci_move(mov.uci_move()));
+                uci_positions.push(board.fen());
+            }
+            let positions: Vec<String> = replayed.positions().map(|board| board.fen()).collect();
+            assert_eq!(positions, uci_positions);
+            assert_eq!(replayed.current_fen(), *uci_positions.last().unwrap());
+        }
     }
 }